@@ -0,0 +1,221 @@
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Separator of archive path and member name, e.g. `data.zip:docs/a.txt`
+pub const MEMBER_SEPARATOR: char = ':';
+
+/// Archive format recognized by file name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    /// Gzip compressed tar, `.tar.gz` or `.tgz`
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// File inside archive addressed as `archive.zip:path/inside`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub archive: PathBuf,
+    pub name: String,
+}
+
+impl ArchiveMember {
+    /// Splits path at the first separator preceded by path of existing archive file
+    pub fn parse(path: &Path) -> Option<Self> {
+        let path = path.to_str()?;
+        path.match_indices(MEMBER_SEPARATOR).find_map(|(index, _)| {
+            let archive = Path::new(&path[..index]);
+            (ArchiveKind::of(archive).is_some() && archive.is_file()).then(|| ArchiveMember {
+                archive: archive.to_path_buf(),
+                name: path[index + 1..].to_string(),
+            })
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}{MEMBER_SEPARATOR}{}",
+            self.archive.display(),
+            self.name
+        ))
+    }
+
+    /// Reads uncompressed content of the member. Tar archives are scanned from the beginning.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        match ArchiveKind::of(&self.archive) {
+            Some(ArchiveKind::Zip) => {
+                let mut archive = zip::ZipArchive::new(BufReader::new(File::open(&self.archive)?))?;
+                archive.by_name(&self.name)?.read_to_end(&mut content)?;
+            }
+            Some(_) => {
+                let mut archive = open_tar(&self.archive)?;
+                let mut entry = tar_files(&mut archive)?
+                    .find(|entry| entry.as_ref().map_or(true, |(name, _)| *name == self.name))
+                    .ok_or_else(|| not_found(self))??
+                    .1;
+                entry.read_to_end(&mut content)?;
+            }
+            None => return Err(not_found(self)),
+        }
+        Ok(content)
+    }
+
+    /// Uncompressed size of the member
+    pub fn size(&self) -> io::Result<u64> {
+        list_members(&self.archive)?
+            .into_iter()
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, size)| size)
+            .ok_or_else(|| not_found(self))
+    }
+}
+
+/// Names and uncompressed sizes of files in archive, directories are skipped
+pub fn list_members(archive: &Path) -> io::Result<Vec<(String, u64)>> {
+    match ArchiveKind::of(archive) {
+        Some(ArchiveKind::Zip) => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+            let mut members = Vec::new();
+            for index in 0..zip.len() {
+                let file = zip.by_index(index)?;
+                if file.is_file() {
+                    members.push((file.name().to_string(), file.size()));
+                }
+            }
+            Ok(members)
+        }
+        Some(_) => {
+            let mut tar = open_tar(archive)?;
+            tar_files(&mut tar)?
+                .map(|entry| entry.map(|(name, entry)| (name, entry.size())))
+                .collect()
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Paths of archive members selected by `member`: the member itself or all members inside
+/// directory `member`. Empty `member` selects all members.
+pub fn member_paths(archive: &Path, member: &str) -> io::Result<Vec<PathBuf>> {
+    let directory = format!("{}/", member.trim_end_matches('/'));
+    Ok(list_members(archive)?
+        .into_iter()
+        .filter(|(name, _)| member.is_empty() || name == member || name.starts_with(&directory))
+        .map(|(name, _)| {
+            ArchiveMember {
+                archive: archive.to_path_buf(),
+                name,
+            }
+            .path()
+        })
+        .collect())
+}
+
+fn open_tar(archive: &Path) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match ArchiveKind::of(archive) {
+        Some(ArchiveKind::TarGz) => Box::new(MultiGzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Name of tar member together with entry to read it
+type TarFile<'a> = (String, tar::Entry<'a, Box<dyn Read>>);
+
+/// Regular file entries of tar archive together with names without leading `./`
+fn tar_files<'a>(
+    archive: &'a mut tar::Archive<Box<dyn Read>>,
+) -> io::Result<impl Iterator<Item = io::Result<TarFile<'a>>>> {
+    Ok(archive.entries()?.filter_map(|entry| {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        if !entry.header().entry_type().is_file() {
+            return None;
+        }
+        let name = match entry.path() {
+            Ok(path) => path.to_string_lossy().trim_start_matches("./").to_string(),
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok((name, entry)))
+    }))
+}
+
+fn not_found(member: &ArchiveMember) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found in {}", member.name, member.archive.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_zip_and_tar_members() {
+        let dir = std::env::temp_dir().join(format!("tokencount-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("data.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("docs/", options).unwrap();
+        zip.start_file("docs/a.txt", options).unwrap();
+        zip.write_all(b"hello").unwrap();
+        zip.start_file("b.txt", options).unwrap();
+        zip.write_all(b"world!").unwrap();
+        zip.finish().unwrap();
+        let tar_path = dir.join("data.tar");
+        let mut tar = tar::Builder::new(File::create(&tar_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        tar.append_data(&mut header, "./docs/a.txt", &b"hello"[..])
+            .unwrap();
+        tar.finish().unwrap();
+        drop(tar);
+
+        let zip_member = ArchiveMember::parse(&dir.join("data.zip:docs/a.txt")).unwrap();
+        let zip_content = zip_member.read().unwrap();
+        let zip_members = member_paths(&zip_path, "docs").unwrap();
+        let all_zip_members = member_paths(&zip_path, "").unwrap();
+        let tar_member = ArchiveMember::parse(&dir.join("data.tar:docs/a.txt")).unwrap();
+        let tar_content = tar_member.read().unwrap();
+        let tar_size = tar_member.size().unwrap();
+        let missing = ArchiveMember::parse(&dir.join("data.tar:c.txt"))
+            .unwrap()
+            .read();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(zip_member.archive, zip_path);
+        assert_eq!(zip_member.name, "docs/a.txt");
+        assert_eq!(zip_content, b"hello");
+        assert_eq!(zip_members, vec![dir.join("data.zip:docs/a.txt")]);
+        assert_eq!(all_zip_members.len(), 2);
+        assert_eq!(tar_content, b"hello");
+        assert_eq!(tar_size, 5);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(ArchiveMember::parse(Path::new("missing.zip:a.txt")).is_none());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::Result;
+use clap::ValueEnum;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tiktoken_rs::CoreBPE;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+/// Token with byte range of text it was produced from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenOffset {
+    pub id: u32,
+    pub token: String,
+    /// Start byte of token in text, inclusive
+    pub start: usize,
+    /// End byte of token in text, exclusive. Special tokens have empty range
+    pub end: usize,
+}
+
+/// Common interface of tokenizer backends
+pub trait CountTokens: Send + Sync {
+    /// Returns number of tokens in text.
+    /// `add_special_tokens` adds tokens of model template, like `[CLS]` and `[SEP]`.
+    fn count(&self, text: &str, add_special_tokens: bool) -> Result<usize>;
+
+    /// Returns number of tokens for each text
+    fn count_batch(&self, texts: Vec<String>, add_special_tokens: bool) -> Result<Vec<usize>> {
+        texts
+            .iter()
+            .map(|text| self.count(text, add_special_tokens))
+            .collect()
+    }
+
+    /// Hash of tokenizer definition to detect that another tokenizer is used,
+    /// `None` if backend has no definition to hash
+    fn fingerprint(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns tokens of text with their byte offsets, backends without offset information
+    /// return an error
+    fn offsets(&self, _text: &str, _add_special_tokens: bool) -> Result<Vec<TokenOffset>> {
+        Err("Tokenizer does not provide token offsets".into())
+    }
+
+    /// Number of tokens in vocabulary including special tokens, `None` if backend has
+    /// no vocabulary
+    fn vocab_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Special tokens of vocabulary ordered by id
+    fn special_tokens(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Count of a file is its stored size, so files are not read
+    fn counts_file_size(&self) -> bool {
+        false
+    }
+
+    /// Adjusts count of a whole text, like truncation and padding of model input.
+    /// Applied once per file after its windows or batch are counted.
+    fn limit(&self, tokens: usize) -> usize {
+        tokens
+    }
+}
+
+impl CountTokens for Tokenizer {
+    fn count(&self, text: &str, add_special_tokens: bool) -> Result<usize> {
+        self.encode(text, add_special_tokens).map(|enc| enc.len())
+    }
+
+    fn count_batch(&self, texts: Vec<String>, add_special_tokens: bool) -> Result<Vec<usize>> {
+        self.encode_batch(texts, add_special_tokens)
+            .map(|vec| vec.iter().map(|enc| enc.len()).collect())
+    }
+
+    /// Blake3 hash of tokenizer serialized to JSON
+    fn fingerprint(&self) -> Option<String> {
+        let json = self.to_string(false).ok()?;
+        Some(blake3::hash(json.as_bytes()).to_hex().to_string())
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        Some(self.get_vocab_size(true))
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        let mut special: Vec<(u32, String)> = self
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, token)| (id, token.content))
+            .collect();
+        special.sort();
+        special.into_iter().map(|(_, token)| token).collect()
+    }
+
+    fn offsets(&self, text: &str, add_special_tokens: bool) -> Result<Vec<TokenOffset>> {
+        let encoding = self.encode(text, add_special_tokens)?;
+        let tokens = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_tokens())
+            .zip(encoding.get_offsets())
+            .map(|((id, token), (start, end))| TokenOffset {
+                id: *id,
+                token: token.clone(),
+                start: *start,
+                end: *end,
+            })
+            .collect();
+        Ok(tokens)
+    }
+}
+
+/// Tokenizer with truncation and padding of its configuration applied to count of each
+/// text separately. Text is encoded without them, so counts of windows can be summed and
+/// padding does not depend on other texts of the batch.
+pub struct ConfiguredTokenizer {
+    tokenizer: Tokenizer,
+    truncation: Option<TruncationParams>,
+    padding: Option<PaddingParams>,
+    fingerprint: Option<String>,
+}
+
+impl ConfiguredTokenizer {
+    pub fn new(mut tokenizer: Tokenizer) -> Result<Self> {
+        let fingerprint = tokenizer.fingerprint();
+        let truncation = tokenizer.get_truncation().cloned();
+        let padding = tokenizer.get_padding().cloned();
+        tokenizer.with_truncation(None)?.with_padding(None);
+        Ok(ConfiguredTokenizer {
+            tokenizer,
+            truncation,
+            padding,
+            fingerprint,
+        })
+    }
+}
+
+impl CountTokens for ConfiguredTokenizer {
+    /// Number of tokens before truncation and padding, see `limit`
+    fn count(&self, text: &str, add_special_tokens: bool) -> Result<usize> {
+        self.tokenizer.count(text, add_special_tokens)
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.fingerprint.clone()
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        self.tokenizer.vocab_size()
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        self.tokenizer.special_tokens()
+    }
+
+    fn limit(&self, tokens: usize) -> usize {
+        let mut tokens = match &self.truncation {
+            Some(truncation) => tokens.min(truncation.max_length),
+            None => tokens,
+        };
+        if let Some(padding) = &self.padding {
+            // Single text is the longest of its own batch
+            if let PaddingStrategy::Fixed(length) = padding.strategy {
+                tokens = tokens.max(length);
+            }
+            if let Some(multiple) = padding.pad_to_multiple_of.filter(|multiple| *multiple > 0) {
+                tokens = tokens.next_multiple_of(multiple);
+            }
+        }
+        tokens
+    }
+}
+
+/// Unit of measure counted instead of tokens without tokenizer
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextUnit {
+    /// Number of newline characters, like `wc -l`
+    Lines,
+    /// Number of words separated by unicode whitespace
+    Words,
+    /// Number of bytes of UTF-8 text, size on disk for files
+    Bytes,
+}
+
+impl CountTokens for TextUnit {
+    fn count(&self, text: &str, _add_special_tokens: bool) -> Result<usize> {
+        Ok(match self {
+            TextUnit::Lines => text.bytes().filter(|byte| *byte == b'\n').count(),
+            TextUnit::Words => text.split_whitespace().count(),
+            TextUnit::Bytes => text.len(),
+        })
+    }
+
+    fn counts_file_size(&self) -> bool {
+        *self == TextUnit::Bytes
+    }
+}
+
+/// OpenAI BPE encodings supported by tiktoken
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    #[value(name = "o200k_base")]
+    O200kBase,
+    #[value(name = "cl100k_base")]
+    Cl100kBase,
+    #[value(name = "p50k_base")]
+    P50kBase,
+    #[value(name = "p50k_edit")]
+    P50kEdit,
+    #[value(name = "r50k_base")]
+    R50kBase,
+}
+
+impl Encoding {
+    /// Number of token ids, ids of special tokens follow regular tokens
+    pub fn vocab_size(self) -> usize {
+        match self {
+            Encoding::O200kBase => 200_019,
+            Encoding::Cl100kBase => 100_277,
+            Encoding::P50kBase => 50_281,
+            Encoding::P50kEdit => 50_284,
+            Encoding::R50kBase => 50_257,
+        }
+    }
+}
+
+/// Tiktoken BPE backend
+pub struct TiktokenCounter {
+    encoding: Encoding,
+    bpe: CoreBPE,
+}
+
+impl TiktokenCounter {
+    pub fn new(encoding: Encoding) -> Result<Self> {
+        let bpe = match encoding {
+            Encoding::O200kBase => tiktoken_rs::o200k_base(),
+            Encoding::Cl100kBase => tiktoken_rs::cl100k_base(),
+            Encoding::P50kBase => tiktoken_rs::p50k_base(),
+            Encoding::P50kEdit => tiktoken_rs::p50k_edit(),
+            Encoding::R50kBase => tiktoken_rs::r50k_base(),
+        }?;
+        Ok(TiktokenCounter { encoding, bpe })
+    }
+}
+
+/// Tiktoken encodings do not have template tokens, so `add_special_tokens` is ignored
+impl CountTokens for TiktokenCounter {
+    fn count(&self, text: &str, _add_special_tokens: bool) -> Result<usize> {
+        Ok(self.bpe.encode_ordinary(text).len())
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        Some(self.encoding.vocab_size())
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        let mut special: Vec<(u32, &str)> = self
+            .bpe
+            .special_tokens()
+            .into_iter()
+            .map(|token| (self.bpe.encode_with_special_tokens(token)[0], token))
+            .collect();
+        special.sort();
+        special
+            .into_iter()
+            .map(|(_, token)| token.to_string())
+            .collect()
+    }
+
+    /// Offsets are sums of byte lengths of preceding tokens, tokens which are not valid
+    /// UTF-8 on their own are decoded lossily
+    fn offsets(&self, text: &str, _add_special_tokens: bool) -> Result<Vec<TokenOffset>> {
+        let ids = self.bpe.encode_ordinary(text);
+        let mut start = 0;
+        let tokens = ids
+            .iter()
+            .zip(self.bpe._decode_native_and_split(ids.clone()))
+            .map(|(id, bytes)| {
+                let offset = TokenOffset {
+                    id: *id,
+                    token: String::from_utf8_lossy(&bytes).to_string(),
+                    start,
+                    end: start + bytes.len(),
+                };
+                start = offset.end;
+                offset
+            })
+            .collect();
+        Ok(tokens)
+    }
+}
+
+/// Backend delegating counting to external command, which reads text from stdin and
+/// prints token count to stdout. Command is started once per text.
+pub struct CommandCounter {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandCounter {
+    /// Parses command line split by whitespace, quoting is not supported
+    pub fn new(command: &str) -> Result<Self> {
+        let mut parts = command.split_whitespace().map(|part| part.to_string());
+        let program = parts.next().ok_or("Tokenizer command is empty")?;
+        Ok(CommandCounter {
+            program,
+            args: parts.collect(),
+        })
+    }
+}
+
+/// Special tokens are up to the command, so `add_special_tokens` is ignored
+impl CountTokens for CommandCounter {
+    fn count(&self, text: &str, _add_special_tokens: bool) -> Result<usize> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Cannot start tokenizer command {}: {e}", self.program))?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or("Cannot open stdin of tokenizer command")?;
+        // Text is written from another thread, so command output never blocks the write
+        let output = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(text.as_bytes()));
+            let output = child.wait_with_output();
+            // Command may exit without reading whole input, its status decides
+            let _ = writer.join();
+            output
+        })?;
+        if !output.status.success() {
+            return Err(format!(
+                "Tokenizer command {} failed with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.trim().parse().map_err(|_| {
+            let printed: String = stdout.trim().chars().take(80).collect();
+            format!(
+                "Tokenizer command {} printed {printed:?} instead of token count",
+                self.program
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiktoken_count() {
+        let counter = TiktokenCounter::new(Encoding::Cl100kBase).unwrap();
+        assert_eq!(counter.count("hello world", false).unwrap(), 2);
+        assert_eq!(
+            counter
+                .count_batch(vec!["hello".to_string(), "hello world".to_string()], false)
+                .unwrap(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_tiktoken_offsets() {
+        let counter = TiktokenCounter::new(Encoding::Cl100kBase).unwrap();
+        let offsets = counter.offsets("hello wörld", false).unwrap();
+        let ranges: Vec<(usize, usize)> = offsets.iter().map(|o| (o.start, o.end)).collect();
+        assert_eq!(ranges[0], (0, 5));
+        assert_eq!(ranges.last().map(|range| range.1), Some(12));
+        assert!(ranges.windows(2).all(|pair| pair[0].1 == pair[1].0));
+        assert_eq!(offsets[0].token, "hello");
+        assert!(TextUnit::Words.offsets("hello", false).is_err());
+    }
+
+    #[test]
+    fn test_vocab_info() {
+        let counter = TiktokenCounter::new(Encoding::Cl100kBase).unwrap();
+        assert_eq!(counter.vocab_size(), Some(100_277));
+        assert_eq!(counter.special_tokens()[0], "<|endoftext|>");
+        assert_eq!(counter.special_tokens().len(), 5);
+        let tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        assert_eq!(tokenizer.vocab_size(), Some(9));
+        assert_eq!(tokenizer.special_tokens(), vec!["[UNK]", "[CLS]", "[SEP]"]);
+        assert_eq!(TextUnit::Words.vocab_size(), None);
+        assert!(TextUnit::Words.special_tokens().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_count() {
+        let counter = CommandCounter::new("wc -c").unwrap();
+        assert_eq!(counter.count("hello world", false).unwrap(), 11);
+        assert!(
+            CommandCounter::new("cat")
+                .unwrap()
+                .count("hi", false)
+                .is_err()
+        );
+        assert!(CommandCounter::new(" ").is_err());
+    }
+
+    #[test]
+    fn test_text_unit_count() {
+        let text = "hello wörld\nfoo\n";
+        assert_eq!(TextUnit::Lines.count(text, false).unwrap(), 2);
+        assert_eq!(TextUnit::Words.count(text, false).unwrap(), 3);
+        assert_eq!(TextUnit::Bytes.count(text, false).unwrap(), 17);
+    }
+
+    #[test]
+    fn test_configured_tokenizer_limit() {
+        let mut tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: 10,
+                ..TruncationParams::default()
+            }))
+            .unwrap()
+            .with_padding(Some(PaddingParams {
+                strategy: PaddingStrategy::Fixed(4),
+                pad_to_multiple_of: Some(3),
+                ..PaddingParams::default()
+            }));
+        let configured = ConfiguredTokenizer::new(tokenizer.clone()).unwrap();
+        // Text is encoded without truncation and padding
+        assert_eq!(configured.count("Hello world", true).unwrap(), 4);
+        assert_eq!(configured.limit(0), 6);
+        assert_eq!(configured.limit(7), 9);
+        assert_eq!(configured.limit(100), 12);
+        assert_eq!(configured.fingerprint(), tokenizer.fingerprint());
+        assert_eq!(TextUnit::Words.limit(100), 100);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::Result;
+use minijinja::{Environment, ErrorKind, context};
+use serde_json::Value;
+use std::path::Path;
+
+/// Tokenizer config of Hugging Face models stored next to `tokenizer.json`
+pub const TOKENIZER_CONFIG_FILE: &str = "tokenizer_config.json";
+const TEMPLATE_NAME: &str = "chat";
+
+/// Jinja chat template of a model, e.g. `chat_template` of `tokenizer_config.json`.
+/// Text is rendered as a single user message, so counts include role markers.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    /// Compiles template source, syntax errors are returned immediately
+    pub fn new(source: &str) -> Result<Self> {
+        let mut env = Environment::new();
+        // Same settings as `apply_chat_template` of transformers
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        minijinja_contrib::add_to_environment(&mut env);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| {
+            Err::<String, _>(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_template_owned(TEMPLATE_NAME, source.to_string())?;
+        Ok(ChatTemplate {
+            env,
+            bos_token: String::new(),
+            eos_token: String::new(),
+        })
+    }
+
+    /// Values of `bos_token` and `eos_token` template variables
+    pub fn with_special_tokens(mut self, bos_token: String, eos_token: String) -> Self {
+        self.bos_token = bos_token;
+        self.eos_token = eos_token;
+        self
+    }
+
+    /// Reads template and special tokens from tokenizer config, `None` if the config has no
+    /// chat template. Of several named templates the `default` one is used.
+    pub fn from_tokenizer_config(path: &Path) -> Result<Option<Self>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read tokenizer config {}: {e}", path.display()))?;
+        let config: Value = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid tokenizer config {}: {e}", path.display()))?;
+        let source = match &config["chat_template"] {
+            Value::String(source) => Some(source.as_str()),
+            Value::Array(templates) => templates
+                .iter()
+                .find(|template| template["name"] == "default")
+                .or(templates.first())
+                .and_then(|template| template["template"].as_str()),
+            _ => None,
+        };
+        let Some(source) = source else {
+            return Ok(None);
+        };
+        // Special tokens are plain strings or added token objects with `content`
+        let token = |key: &str| {
+            let token = &config[key];
+            token
+                .as_str()
+                .or_else(|| token["content"].as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        let template =
+            ChatTemplate::new(source)?.with_special_tokens(token("bos_token"), token("eos_token"));
+        Ok(Some(template))
+    }
+
+    /// Loads tokenizer config from `.json` file, any other file is a template itself
+    pub fn load(path: &Path) -> Result<Self> {
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            return ChatTemplate::from_tokenizer_config(path)?
+                .ok_or_else(|| format!("No chat template in {}", path.display()).into());
+        }
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read chat template {}: {e}", path.display()))?;
+        ChatTemplate::new(&source)
+    }
+
+    /// Renders `content` as a single user message without generation prompt
+    pub fn apply(&self, content: &str) -> Result<String> {
+        let template = self.env.get_template(TEMPLATE_NAME)?;
+        let text = template.render(context! {
+            messages => vec![context! { role => "user", content => content }],
+            add_generation_prompt => false,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })?;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_tokenizer_config_template() {
+        let path = std::env::temp_dir().join(format!(
+            "tokencount-{}-{TOKENIZER_CONFIG_FILE}",
+            std::process::id()
+        ));
+        let config = serde_json::json!({
+            "bos_token": {"content": "<s>"},
+            "eos_token": "</s>",
+            "chat_template": "{{ bos_token }}{% for message in messages %}\
+                {% if message['role'] != 'user' %}{{ raise_exception('Only user') }}{% endif %}\
+                [INST] {{ message['content'].strip() }} [/INST]{% endfor %}",
+        });
+        std::fs::write(&path, config.to_string()).unwrap();
+        let template = ChatTemplate::load(&path).unwrap();
+        std::fs::write(&path, "{\"bos_token\": \"<s>\"}").unwrap();
+        let missing = ChatTemplate::from_tokenizer_config(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            template.apply(" Hello world\n").unwrap(),
+            "<s>[INST] Hello world [/INST]"
+        );
+        assert!(missing.is_none());
+        assert!(ChatTemplate::new("{% for %}").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::Result;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// Config file with default flags looked up in the current directory
+pub const CONFIG_FILE: &str = ".tokencount.toml";
+
+/// Defaults of command line flags read from config file, e.g.
+///
+/// ```toml
+/// recursive = true
+/// model = "gpt2"
+/// include = ["*.rs", "*.md"]
+/// exclude-dir = ["target", "node_modules"]
+/// ```
+///
+/// Flags passed on command line take precedence over config values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub recursive: Option<bool>,
+    /// Tokenizer models, a single string or an array
+    pub model: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub exclude_dir: Vec<String>,
+}
+
+impl Config {
+    /// Reads config from `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config {}: {e}", path.display()))?;
+        Config::parse(&content)
+            .map_err(|e| format!("Invalid config {}: {e}", path.display()).into())
+    }
+
+    /// Parses config content, unknown keys are rejected to catch typos
+    pub fn parse(content: &str) -> Result<Self> {
+        let table: Table = content.parse()?;
+        let mut config = Config::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "recursive" => {
+                    let recursive = value
+                        .as_bool()
+                        .ok_or_else(|| format!("{key} must be a boolean"))?;
+                    config.recursive = Some(recursive);
+                }
+                "model" => config.model = string_list(&key, value)?,
+                "include" => config.include = string_list(&key, value)?,
+                "exclude" => config.exclude = string_list(&key, value)?,
+                "exclude-dir" => config.exclude_dir = string_list(&key, value)?,
+                _ => {
+                    return Err(format!(
+                        "Unknown key {key}, expected one of recursive, model, include, \
+                         exclude, exclude-dir"
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Accepts a single string or an array of strings
+fn string_list(key: &str, value: Value) -> Result<Vec<String>> {
+    let error = || format!("{key} must be a string or an array of strings");
+    match value {
+        Value::String(value) => Ok(vec![value]),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(value) => Ok(value),
+                _ => Err(error().into()),
+            })
+            .collect(),
+        _ => Err(error().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "recursive = true\nmodel = \"gpt2\"\nexclude-dir = [\"target\", \".git\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                recursive: Some(true),
+                model: vec!["gpt2".to_string()],
+                exclude_dir: vec!["target".to_string(), ".git".to_string()],
+                ..Config::default()
+            }
+        );
+        assert!(Config::parse("exclude_dir = [\"target\"]").is_err());
+        assert!(Config::parse("include = [1]").is_err());
+        assert!(Config::parse("recursive = \"yes\"").is_err());
+    }
+}
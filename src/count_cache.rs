@@ -0,0 +1,186 @@
+use crate::Result;
+use crate::counter::{FileCount, path_to_string};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Cached counts of a file together with data used to detect file changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CacheEntry {
+    size: u64,
+    /// Modification time in nanoseconds since unix epoch
+    mtime: u64,
+    /// Blake3 hash of file content
+    hash: String,
+    count: FileCount,
+}
+
+/// On-disk cache of token counts keyed by file path. Entry is reused if file size and
+/// modification time are unchanged, or if content hash is unchanged. All entries are
+/// invalidated if tokenizer identity changes.
+pub struct CountCache {
+    path: PathBuf,
+    tokenizer: String,
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl CountCache {
+    /// Loads cache from `path`. Missing file, unreadable file or cache of other tokenizer
+    /// result in empty cache.
+    pub fn load(path: PathBuf, tokenizer: String) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Value>(&content).ok())
+            .filter(|value| value["tokenizer"].as_str() == Some(tokenizer.as_str()))
+            .and_then(|value| value["files"].as_object().map(parse_entries))
+            .unwrap_or_default();
+        CountCache {
+            path,
+            tokenizer,
+            entries,
+        }
+    }
+
+    /// Returns cached count of file if file is not changed since it was cached
+    pub fn lookup(&mut self, file: &Path) -> Option<FileCount> {
+        let key = path_to_string(file);
+        let entry = self.entries.get_mut(&key)?;
+        let (size, mtime) = file_metadata(file)?;
+        if entry.size != size || entry.mtime != mtime {
+            // File is touched or changed, content decides
+            if file_hash(file)? != entry.hash {
+                return None;
+            }
+            entry.size = size;
+            entry.mtime = mtime;
+        }
+        Some(FileCount {
+            path: file.to_path_buf(),
+            ..entry.count.clone()
+        })
+    }
+
+    /// Stores count of file, file is read to compute its hash
+    pub fn insert(&mut self, count: &FileCount) {
+        let (Some((size, mtime)), Some(hash)) =
+            (file_metadata(&count.path), file_hash(&count.path))
+        else {
+            return;
+        };
+        let entry = CacheEntry {
+            size,
+            mtime,
+            hash,
+            count: count.clone(),
+        };
+        self.entries.insert(count.get_path_string(), entry);
+    }
+
+    /// Writes cache to temporary file first and then renames it
+    pub fn save(&self) -> Result<()> {
+        let files: Map<String, Value> = self
+            .entries
+            .iter()
+            .map(|(path, entry)| {
+                let value = json!({
+                    "size": entry.size,
+                    "mtime": entry.mtime,
+                    "hash": entry.hash,
+                    "tokens": entry.count.tokens,
+                    "other_tokens": entry.count.other_tokens,
+                    "words": entry.count.words,
+                    "chars": entry.count.chars,
+                });
+                (path.clone(), value)
+            })
+            .collect();
+        let content = json!({"tokenizer": self.tokenizer, "files": files});
+        let file_name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("cache");
+        let tmp_path = self
+            .path
+            .with_file_name(format!("{file_name}.{}.tmp", std::process::id()));
+        std::fs::write(&tmp_path, content.to_string())?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn parse_entries(files: &Map<String, Value>) -> HashMap<String, CacheEntry> {
+    files
+        .iter()
+        .filter_map(|(path, value)| {
+            let number = |key: &str| value[key].as_u64();
+            let entry = CacheEntry {
+                size: number("size")?,
+                mtime: number("mtime")?,
+                hash: value["hash"].as_str()?.to_string(),
+                count: FileCount {
+                    path: PathBuf::from(path),
+                    tokens: number("tokens")? as usize,
+                    words: number("words")? as usize,
+                    chars: number("chars")? as usize,
+                    other_tokens: value["other_tokens"]
+                        .as_array()?
+                        .iter()
+                        .map(|tokens| tokens.as_u64().map(|tokens| tokens as usize))
+                        .collect::<Option<Vec<usize>>>()?,
+                    ..FileCount::default()
+                },
+            };
+            Some((path.clone(), entry))
+        })
+        .collect()
+}
+
+fn file_metadata(file: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(file).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos() as u64;
+    Some((metadata.len(), mtime))
+}
+
+fn file_hash(file: &Path) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(file).ok()?).ok()?;
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_lookup() {
+        let dir = std::env::temp_dir().join(format!("tokencount-counts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.txt");
+        let cache_file = dir.join("cache.json");
+        std::fs::write(&file, "hello world").unwrap();
+        let count = FileCount::from_text(file.clone(), 2, "hello world");
+        let mut cache = CountCache::load(cache_file.clone(), "bert".to_string());
+        assert!(cache.lookup(&file).is_none());
+        cache.insert(&count);
+        cache.save().unwrap();
+
+        let mut cache = CountCache::load(cache_file.clone(), "bert".to_string());
+        let cached = cache.lookup(&file);
+        let mut other_tokenizer = CountCache::load(cache_file.clone(), "gpt2".to_string());
+        let other_cached = other_tokenizer.lookup(&file);
+        std::fs::write(&file, "hello world!").unwrap();
+        let changed = cache.lookup(&file);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(cached, Some(count));
+        assert!(other_cached.is_none());
+        assert!(changed.is_none());
+    }
+}
@@ -0,0 +1,1211 @@
+use crate::backend::CountTokens;
+use crate::chat_template::ChatTemplate;
+use crate::count_cache::CountCache;
+use crate::files::{
+    ContentWindows, DEFAULT_BINARY_THRESHOLD, DEFAULT_SAMPLE_SIZE, FileContent, FileMatchConfig,
+    FileMatchError, get_matched_files,
+};
+use crate::variables::Variables;
+use encoding_rs::UTF_8;
+use log::debug;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Number of files encoded in one batch if memory and file sizes are not known
+pub const FILE_CHUNK_SIZE: usize = 20;
+/// Upper bound of automatically chosen number of files in one batch
+pub const MAX_CHUNK_SIZE: usize = 1000;
+/// Upper bound of text bytes read by a thread for one batch
+pub const MAX_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+/// Rough memory used by text and its encodings relative to text size
+const ENCODING_MEMORY_FACTOR: u64 = 16;
+/// Size of window in bytes for streamed files
+pub const STREAM_WINDOW_SIZE: usize = 1024 * 1024;
+/// Bytes read per requested token in the first window of `with_head_tokens`
+const HEAD_BYTES_PER_TOKEN: usize = 4;
+/// Size of file in bytes starting from which file is encoded by windows
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Token count of a single file together with simple text statistics
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileCount {
+    pub path: PathBuf,
+    pub tokens: usize,
+    /// Number of words separated by unicode whitespace
+    pub words: usize,
+    /// Number of unicode scalar values
+    pub chars: usize,
+    /// Token counts of additional backends in order they were added to `TokenCounter`
+    pub other_tokens: Vec<usize>,
+    /// Number of tokens beyond model context window
+    pub overflow: usize,
+    /// Number of chunks of sliding window chunking
+    pub chunks: usize,
+}
+
+impl FileCount {
+    pub fn from_text(path: PathBuf, tokens: usize, text: &str) -> Self {
+        FileCount {
+            path,
+            tokens,
+            words: text.split_whitespace().count(),
+            chars: text.chars().count(),
+            other_tokens: Vec::new(),
+            overflow: 0,
+            chunks: 0,
+        }
+    }
+
+    /// Sets counts of all backends, first one is the main count
+    fn set_all_tokens(&mut self, all_tokens: Vec<usize>) {
+        let mut all_tokens = all_tokens.into_iter();
+        self.tokens = all_tokens.next().unwrap_or(0);
+        self.other_tokens = all_tokens.collect();
+    }
+
+    pub fn get_path_string(&self) -> String {
+        path_to_string(&self.path)
+    }
+}
+
+/// Reason of `SkippedFile` for files rejected by text detection
+pub const BINARY_FILE_REASON: &str = "binary file";
+
+/// File which was not counted together with the reason, e.g. binary or unreadable file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Counts tokens in strings and files using wrapped tokenizer backend
+pub struct TokenCounter {
+    backend: Box<dyn CountTokens>,
+    other_backends: Vec<Box<dyn CountTokens>>,
+    stream_threshold: u64,
+    detect_encoding: bool,
+    normalize_newlines: bool,
+    add_special_tokens: bool,
+    max_file_size: Option<u64>,
+    sample_size: usize,
+    binary_threshold: f64,
+    read_errors: AtomicUsize,
+    skipped: Mutex<Vec<SkippedFile>>,
+    quiet: bool,
+    chunk_size: Option<usize>,
+    head_tokens: Option<usize>,
+    chat_template: Option<ChatTemplate>,
+    variables: Option<Variables>,
+    missing_variables: Mutex<BTreeSet<String>>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl TokenCounter {
+    pub fn new(backend: impl CountTokens + 'static) -> Self {
+        TokenCounter::from_boxed(Box::new(backend))
+    }
+
+    pub fn from_boxed(backend: Box<dyn CountTokens>) -> Self {
+        TokenCounter {
+            backend,
+            other_backends: Vec::new(),
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            detect_encoding: false,
+            normalize_newlines: false,
+            add_special_tokens: false,
+            max_file_size: None,
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            binary_threshold: DEFAULT_BINARY_THRESHOLD,
+            read_errors: AtomicUsize::new(0),
+            skipped: Mutex::new(Vec::new()),
+            quiet: false,
+            chunk_size: None,
+            head_tokens: None,
+            chat_template: None,
+            variables: None,
+            missing_variables: Mutex::new(BTreeSet::new()),
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Adds backend to compare counts with. Its counts are stored in `FileCount::other_tokens`
+    pub fn add_backend(mut self, backend: Box<dyn CountTokens>) -> Self {
+        self.other_backends.push(backend);
+        self
+    }
+
+    /// Files larger than `stream_threshold` bytes are read and encoded by windows
+    pub fn with_stream_threshold(mut self, stream_threshold: u64) -> Self {
+        self.stream_threshold = stream_threshold;
+        self
+    }
+
+    /// Decode non UTF-8 text files with detected encoding instead of skipping them
+    pub fn with_encoding_detection(mut self, detect_encoding: bool) -> Self {
+        self.detect_encoding = detect_encoding;
+        self
+    }
+
+    /// Convert CRLF line endings of files to LF before counting
+    pub fn with_newline_normalization(mut self, normalize_newlines: bool) -> Self {
+        self.normalize_newlines = normalize_newlines;
+        self
+    }
+
+    /// Count special tokens of model template, like `[CLS]` and `[SEP]`
+    pub fn with_special_tokens(mut self, add_special_tokens: bool) -> Self {
+        self.add_special_tokens = add_special_tokens;
+        self
+    }
+
+    /// Skip files larger than `max_file_size` bytes with warning on stderr
+    pub fn with_max_file_size(mut self, max_file_size: Option<u64>) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Number of bytes from the beginning of file used to decide if it is text
+    pub fn with_sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// Files with larger fraction of invalid or non printable bytes in sample are skipped
+    pub fn with_binary_threshold(mut self, binary_threshold: f64) -> Self {
+        self.binary_threshold = binary_threshold;
+        self
+    }
+
+    /// Do not print warnings about skipped files to stderr
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Number of files read and encoded in one batch by a thread, must be positive.
+    /// Larger batches keep more texts and encodings in memory, but encode them with less
+    /// overhead. `None` picks size by available memory and average file size.
+    pub fn with_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Count only beginning of each file, counts are capped at `head_tokens`. Files are read
+    /// by growing windows until prefix has enough tokens, so huge files are not read whole.
+    pub fn with_head_tokens(mut self, head_tokens: Option<usize>) -> Self {
+        self.head_tokens = head_tokens;
+        self
+    }
+
+    /// Texts are rendered as a user message of `chat_template` before encoding, statistics
+    /// of words and chars are computed from original texts
+    pub fn with_chat_template(mut self, chat_template: Option<ChatTemplate>) -> Self {
+        self.chat_template = chat_template;
+        self
+    }
+
+    /// Placeholders of texts are replaced with values of `variables` before encoding and
+    /// before chat template is applied. Statistics of words and chars are computed from
+    /// original texts.
+    pub fn with_variables(mut self, variables: Option<Variables>) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    /// Sorted names of placeholders found in counted texts without value in variables
+    pub fn missing_variables(&self) -> Vec<String> {
+        self.missing_variables
+            .lock()
+            .expect("Missing variables lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Flag set on interruption, e.g. by SIGINT handler. Once it is set, chunks of files which
+    /// are not started yet are skipped and counting returns counts of files processed so far.
+    pub fn with_interrupt_flag(mut self, interrupted: Arc<AtomicBool>) -> Self {
+        self.interrupted = interrupted;
+        self
+    }
+
+    /// Checks if counting was interrupted, counts are partial then
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn count_str(&self, text: &str) -> usize {
+        let tokens = self
+            .backend
+            .count(&self.templated(text), self.add_special_tokens)
+            .expect("Error while encoding text");
+        self.backend.limit(tokens)
+    }
+
+    /// Counts tokens of each text with the main backend, texts are encoded as one batch
+    pub fn count_batch(&self, texts: &[String]) -> crate::Result<Vec<usize>> {
+        let lengths = self
+            .backend
+            .count_batch(self.templated_all(texts.to_vec()), self.add_special_tokens)?;
+        Ok(lengths
+            .into_iter()
+            .map(|tokens| self.backend.limit(tokens))
+            .collect())
+    }
+
+    /// Counts tokens of text with all backends together with text statistics
+    pub fn count_text(&self, path: PathBuf, text: &str) -> FileCount {
+        let mut count = FileCount::from_text(path, 0, text);
+        let text = self.templated(text);
+        self.set_tokens(
+            &mut count,
+            self.backends()
+                .map(|backend| {
+                    backend
+                        .count(&text, self.add_special_tokens)
+                        .expect("Error while encoding text")
+                })
+                .collect(),
+        );
+        count
+    }
+
+    fn backends(&self) -> impl Iterator<Item = &dyn CountTokens> {
+        std::iter::once(self.backend.as_ref()).chain(self.other_backends.iter().map(|b| b.as_ref()))
+    }
+
+    /// Number of files skipped because they could not be read
+    pub fn read_errors(&self) -> usize {
+        self.read_errors.load(Ordering::Relaxed)
+    }
+
+    /// Files skipped because they are binary, too large or unreadable, sorted by path
+    pub fn skipped_files(&self) -> Vec<SkippedFile> {
+        let mut skipped = self
+            .skipped
+            .lock()
+            .expect("Skipped files lock poisoned")
+            .clone();
+        skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        skipped
+    }
+
+    /// Finds files matched to config and counts tokens in each text file
+    pub fn count_files(
+        &self,
+        paths: Vec<String>,
+        config: FileMatchConfig,
+    ) -> Result<Vec<FileCount>, FileMatchError> {
+        Ok(self.count_paths(&get_matched_files(paths, config)?))
+    }
+
+    /// Counts tokens in each text file. Non text and unreadable files are skipped,
+    /// read errors are printed to stderr and counted in `read_errors`.
+    /// Files are processed in parallel by chunks, result keeps order of `files`.
+    pub fn count_paths(&self, files: &[PathBuf]) -> Vec<FileCount> {
+        self.count_paths_with_progress(files, |_| {})
+    }
+
+    /// Same as `count_paths`, `progress` is called with number of processed files
+    /// after each chunk
+    pub fn count_paths_with_progress(
+        &self,
+        files: &[PathBuf],
+        progress: impl Fn(usize) + Sync,
+    ) -> Vec<FileCount> {
+        self.count_paths_with_callback(files, |file_chunk, _| progress(file_chunk.len()))
+    }
+
+    /// Same as `count_paths`, `on_chunk` is called with each chunk of files and their counts
+    /// as soon as the chunk is counted. Chunks are counted in parallel, so calls are not in
+    /// order of `files`.
+    pub fn count_paths_with_callback(
+        &self,
+        files: &[PathBuf],
+        on_chunk: impl Fn(&[PathBuf], &[FileCount]) + Sync,
+    ) -> Vec<FileCount> {
+        let chunk_size = self.chunk_size.unwrap_or_else(|| {
+            let average_size = self.average_batched_size(files);
+            auto_chunk_size(
+                files.len(),
+                average_size,
+                rayon::current_num_threads(),
+                available_memory(),
+            )
+        });
+        let counts: Vec<Vec<FileCount>> = files
+            .par_chunks(chunk_size)
+            .map(|file_chunk| {
+                if self.is_interrupted() {
+                    return Vec::new();
+                }
+                let counts = self.count_chunk(file_chunk);
+                on_chunk(file_chunk, &counts);
+                counts
+            })
+            .collect();
+        counts.into_iter().flatten().collect()
+    }
+
+    /// Same as `count_paths_with_progress`, but counts of files not changed since they were
+    /// stored in `cache` are taken from it. New counts are stored in `cache`.
+    pub fn count_paths_with_cache(
+        &self,
+        files: &[PathBuf],
+        cache: &mut CountCache,
+        progress: impl Fn(usize) + Sync,
+    ) -> Vec<FileCount> {
+        let cached: Vec<Option<FileCount>> = files.iter().map(|file| cache.lookup(file)).collect();
+        let missing: Vec<PathBuf> = files
+            .iter()
+            .zip(&cached)
+            .filter(|(_, count)| count.is_none())
+            .map(|(file, _)| file.clone())
+            .collect();
+        progress(files.len() - missing.len());
+        let counted = self.count_paths_with_progress(&missing, progress);
+        for count in &counted {
+            cache.insert(count);
+        }
+        // Skipped files are absent in counted, so counts are matched by path
+        let mut counted = counted.into_iter().peekable();
+        files
+            .iter()
+            .zip(cached)
+            .filter_map(|(file, cached)| {
+                cached.or_else(|| counted.next_if(|count| &count.path == file))
+            })
+            .collect()
+    }
+
+    /// Counts tokens of each line of text files separately, lines of a file are encoded as
+    /// one batch. Counts are labeled `path:lineno` with line numbers starting from 1.
+    pub fn count_lines(&self, files: &[PathBuf]) -> Vec<FileCount> {
+        let counts: Vec<Vec<FileCount>> = files
+            .par_iter()
+            .map(|file| {
+                let file = self.file_content(file);
+                if !self.is_countable(&file) {
+                    return Vec::new();
+                }
+                let Some(content) = self.skip_read_error(&file, file.read_content()) else {
+                    return Vec::new();
+                };
+                let lines: Vec<String> = content.lines().map(str::to_string).collect();
+                self.count_text_lines(&file.get_path_string(), &lines, 1)
+            })
+            .collect();
+        counts.into_iter().flatten().collect()
+    }
+
+    /// Counts tokens of each line separately with all backends, lines are encoded as one
+    /// batch. Counts are labeled `name:lineno`, the first line has number `first_line`.
+    pub fn count_text_lines(
+        &self,
+        name: &str,
+        lines: &[String],
+        first_line: usize,
+    ) -> Vec<FileCount> {
+        let counts: Vec<FileCount> = lines
+            .iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let path = format!("{name}:{}", first_line + index);
+                FileCount::from_text(PathBuf::from(path), 0, line)
+            })
+            .collect();
+        self.count_texts(counts, lines)
+    }
+
+    /// Counts tokens of path strings instead of contents of files, files are not read.
+    /// Paths are encoded as one batch.
+    pub fn count_path_names(&self, files: &[PathBuf]) -> Vec<FileCount> {
+        let names: Vec<String> = files.iter().map(|file| path_to_string(file)).collect();
+        let counts: Vec<FileCount> = files
+            .iter()
+            .zip(&names)
+            .map(|(file, name)| FileCount::from_text(file.clone(), 0, name))
+            .collect();
+        self.count_texts(counts, &names)
+    }
+
+    /// Sets token counts of all backends of `texts` encoded as one batch to `counts` of
+    /// the same order
+    fn count_texts(&self, mut counts: Vec<FileCount>, texts: &[String]) -> Vec<FileCount> {
+        let all_lengths: Vec<Vec<usize>> = self
+            .backends()
+            .map(|backend| {
+                backend
+                    .count_batch(self.templated_all(texts.to_vec()), self.add_special_tokens)
+                    .expect("Error while encoding text")
+            })
+            .collect();
+        for (index, count) in counts.iter_mut().enumerate() {
+            self.set_tokens(
+                count,
+                all_lengths.iter().map(|lengths| lengths[index]).collect(),
+            );
+        }
+        counts
+    }
+
+    /// Counts text files joined with `separator` as one document labeled `path`. Joined text
+    /// larger than stream threshold is encoded by windows, so only one window is kept in memory.
+    pub fn count_concatenated(
+        &self,
+        files: &[PathBuf],
+        separator: &str,
+        path: PathBuf,
+    ) -> FileCount {
+        let files: Vec<FileContent> = files
+            .iter()
+            .map(|file| self.file_content(file))
+            .filter(|file| self.is_countable(file))
+            .collect();
+        let size: u64 = files.iter().map(|file| file.size()).sum::<u64>()
+            + (separator.len() * files.len().saturating_sub(1)) as u64;
+        let parts = files
+            .iter()
+            .filter_map(|file| self.skip_read_error(file, file.read_content()))
+            .enumerate()
+            .flat_map(|(index, content)| {
+                let separator = (index > 0).then(|| separator.as_bytes().to_vec());
+                separator.into_iter().chain([content.into_bytes()])
+            });
+        let mut reader = PartsReader {
+            parts,
+            current: Cursor::new(Vec::new()),
+        };
+        if size <= self.stream_threshold {
+            let mut text = String::new();
+            // Parts are valid UTF-8 strings, so reading cannot fail
+            reader
+                .read_to_string(&mut text)
+                .expect("Error while joining files");
+            return self.count_text(path, &text);
+        }
+        self.count_windows(path, ContentWindows::new(reader, STREAM_WINDOW_SIZE))
+            .expect("Error while joining files")
+    }
+
+    /// Encodes large file window by window and sums counts of all windows
+    fn count_streamed(&self, file: &FileContent) -> io::Result<FileCount> {
+        self.count_windows(
+            file.path().to_path_buf(),
+            file.read_windows(STREAM_WINDOW_SIZE)?,
+        )
+    }
+
+    /// Encodes growing prefix of file until it has at least `head_tokens` tokens of the main
+    /// backend or file ends. Prefix is encoded again each time its size doubles.
+    fn count_head(&self, file: &FileContent, head_tokens: usize) -> io::Result<FileCount> {
+        let window_size = head_tokens.max(1) * HEAD_BYTES_PER_TOKEN;
+        let windows: Box<dyn Iterator<Item = io::Result<String>>> = if file.encoding() == UTF_8 {
+            Box::new(file.read_windows(window_size)?)
+        } else {
+            // Only UTF-8 content can be split into windows
+            Box::new(std::iter::once(file.read_content()))
+        };
+        let mut prefix = String::new();
+        let mut encoded_size = window_size;
+        for window in windows {
+            prefix.push_str(&window?);
+            if prefix.len() < encoded_size {
+                continue;
+            }
+            if self.count_str(&prefix) >= head_tokens {
+                break;
+            }
+            encoded_size = prefix.len() * 2;
+        }
+        let mut count = self.count_text(file.path().to_path_buf(), &prefix);
+        let tokens = std::iter::once(count.tokens)
+            .chain(count.other_tokens.iter().copied())
+            .map(|tokens| tokens.min(head_tokens))
+            .collect();
+        count.set_all_tokens(tokens);
+        Ok(count)
+    }
+
+    fn count_windows(
+        &self,
+        path: PathBuf,
+        windows: impl Iterator<Item = io::Result<String>>,
+    ) -> io::Result<FileCount> {
+        let mut count = FileCount {
+            path,
+            ..FileCount::default()
+        };
+        let mut all_tokens = vec![0; self.other_backends.len() + 1];
+        let mut add_tokens = |text: &str| {
+            // Special tokens are added once per file, not per window
+            for (tokens, backend) in all_tokens.iter_mut().zip(self.backends()) {
+                *tokens += backend
+                    .count(text, false)
+                    .expect("Error while encoding text");
+            }
+        };
+        // Placeholder split between windows is substituted in the window where it ends
+        let mut carry = String::new();
+        for window in windows {
+            let window = window?;
+            let window_count = FileCount::from_text(PathBuf::new(), 0, &window);
+            count.words += window_count.words;
+            count.chars += window_count.chars;
+            if self.variables.is_some() {
+                let mut text = std::mem::take(&mut carry) + &window;
+                carry = text.split_off(Variables::unterminated_start(&text));
+                add_tokens(&self.substituted(&text));
+            } else {
+                add_tokens(&window);
+            }
+        }
+        if !carry.is_empty() {
+            add_tokens(&self.substituted(&carry));
+        }
+        if self.add_special_tokens {
+            for (tokens, backend) in all_tokens.iter_mut().zip(self.backends()) {
+                *tokens += special_tokens_overhead(backend);
+            }
+        }
+        // Windows are not rendered one by one, tokens of template are added once per file
+        if self.chat_template.is_some() {
+            let template = self.templated("");
+            for (tokens, backend) in all_tokens.iter_mut().zip(self.backends()) {
+                *tokens += backend
+                    .count(&template, false)
+                    .expect("Error while encoding text");
+            }
+        }
+        self.set_tokens(&mut count, all_tokens);
+        Ok(count)
+    }
+
+    /// Sets counts of all backends to `count`, each backend limits count of the whole file
+    fn set_tokens(&self, count: &mut FileCount, all_tokens: Vec<usize>) {
+        count.set_all_tokens(
+            self.backends()
+                .zip(all_tokens)
+                .map(|(backend, tokens)| backend.limit(tokens))
+                .collect(),
+        );
+    }
+
+    /// Substitutes variables and renders text as user message of chat template if they are set
+    fn templated<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = self.substituted(text);
+        match &self.chat_template {
+            Some(template) => Cow::Owned(
+                template
+                    .apply(&text)
+                    .expect("Error while applying chat template"),
+            ),
+            None => text,
+        }
+    }
+
+    /// Substitutes variables if they are set, missing ones are recorded
+    fn substituted<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let Some(variables) = &self.variables else {
+            return Cow::Borrowed(text);
+        };
+        let (text, missing) = variables.substitute(text);
+        if !missing.is_empty() {
+            self.missing_variables
+                .lock()
+                .expect("Missing variables lock poisoned")
+                .extend(missing.into_iter().map(str::to_string));
+        }
+        text
+    }
+
+    fn templated_all(&self, texts: Vec<String>) -> Vec<String> {
+        if self.chat_template.is_none() && self.variables.is_none() {
+            return texts;
+        }
+        texts
+            .iter()
+            .map(|text| self.templated(text).into_owned())
+            .collect()
+    }
+
+    /// Returns value of successful read, otherwise prints error and counts it
+    fn skip_read_error<T>(&self, file: &FileContent, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.warn(&format!("Cannot read file {}: {e}", file.get_path_string()));
+                self.read_errors.fetch_add(1, Ordering::Relaxed);
+                self.skip(file, format!("cannot read: {e}"));
+                None
+            }
+        }
+    }
+
+    fn skip(&self, file: &FileContent, reason: String) {
+        debug!("Skipping file {}: {reason}", file.get_path_string());
+        self.skipped
+            .lock()
+            .expect("Skipped files lock poisoned")
+            .push(SkippedFile {
+                path: file.path().to_path_buf(),
+                reason,
+            });
+    }
+
+    fn warn(&self, message: &str) {
+        if !self.quiet {
+            eprintln!("{message}");
+        }
+    }
+
+    fn is_size_allowed(&self, file: &FileContent) -> bool {
+        match self.max_file_size {
+            Some(max_file_size) if file.size() > max_file_size => {
+                let reason = format!("size {} bytes exceeds {} bytes", file.size(), max_file_size);
+                self.warn(&format!(
+                    "Skipping file {}: {reason}",
+                    file.get_path_string()
+                ));
+                self.skip(file, reason);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Average size of files loaded into memory at once, streamed files are read by
+    /// windows of fixed size
+    fn average_batched_size(&self, files: &[PathBuf]) -> u64 {
+        if files.is_empty() {
+            return 0;
+        }
+        let total: u64 = files
+            .par_iter()
+            .map(|file| {
+                let size = std::fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+                if size > self.stream_threshold {
+                    STREAM_WINDOW_SIZE as u64
+                } else {
+                    size
+                }
+            })
+            .sum();
+        total / files.len() as u64
+    }
+
+    fn file_content(&self, file: &Path) -> FileContent {
+        FileContent::new(file.to_path_buf())
+            .with_encoding_detection(self.detect_encoding)
+            .with_newline_normalization(self.normalize_newlines)
+            .with_sample_size(self.sample_size)
+            .with_binary_threshold(self.binary_threshold)
+    }
+
+    /// Checks that file is text file of allowed size, read errors are counted
+    fn is_countable(&self, file: &FileContent) -> bool {
+        if !self.is_size_allowed(file) {
+            return false;
+        }
+        let is_text = self.skip_read_error(file, file.check_text_file());
+        if is_text == Some(false) {
+            self.skip(file, BINARY_FILE_REASON.to_string());
+        }
+        is_text.unwrap_or(false)
+    }
+
+    fn count_chunk(&self, file_chunk: &[PathBuf]) -> Vec<FileCount> {
+        if self.other_backends.is_empty() && self.backend.counts_file_size() {
+            // Stored size is counted, so files are neither read nor checked for binary content
+            return file_chunk
+                .iter()
+                .map(|file| self.file_content(file))
+                .filter(|file| self.is_size_allowed(file))
+                .filter_map(|file| {
+                    let size = self.skip_read_error(&file, file.stored_size())?;
+                    Some(FileCount {
+                        path: file.path().to_path_buf(),
+                        tokens: size as usize,
+                        ..FileCount::default()
+                    })
+                })
+                .collect();
+        }
+        if let Some(head_tokens) = self.head_tokens {
+            return file_chunk
+                .iter()
+                .map(|file| self.file_content(file))
+                .filter(|file| self.is_countable(file))
+                .filter_map(|file| self.skip_read_error(&file, self.count_head(&file, head_tokens)))
+                .collect();
+        }
+        // Content of streamed files is `None`, it is read later by windows
+        let (files, contents): (Vec<FileContent>, Vec<Option<String>>) = file_chunk
+            .iter()
+            .map(|file| self.file_content(file))
+            .filter(|file| self.is_countable(file))
+            .filter_map(|file| {
+                // Only UTF-8 content can be split into windows
+                if file.size() > self.stream_threshold && file.encoding() == UTF_8 {
+                    return Some((file, None));
+                }
+                let content = self.skip_read_error(&file, file.read_content())?;
+                Some((file, Some(content)))
+            })
+            .unzip();
+        let mut batch_counts: Vec<FileCount> = files
+            .iter()
+            .zip(&contents)
+            .filter_map(|(file, content)| {
+                let content = content.as_ref()?;
+                Some(FileCount::from_text(file.path().to_path_buf(), 0, content))
+            })
+            .collect();
+        let streamed: Vec<bool> = contents.iter().map(Option::is_none).collect();
+        let data: Vec<String> = self.templated_all(contents.into_iter().flatten().collect());
+        let backends: Vec<&dyn CountTokens> = self.backends().collect();
+        let mut all_lengths: Vec<Vec<usize>> = vec![Vec::new(); batch_counts.len()];
+        let mut data = Some(data);
+        for (i, backend) in backends.iter().enumerate() {
+            // Texts are moved into the last backend and cloned for others
+            let texts = if i + 1 == backends.len() {
+                data.take().unwrap_or_default()
+            } else {
+                data.clone().unwrap_or_default()
+            };
+            let lengths = backend
+                .count_batch(texts, self.add_special_tokens)
+                .expect("Error while encoding text");
+            for (file_lengths, length) in all_lengths.iter_mut().zip(lengths) {
+                file_lengths.push(length);
+            }
+        }
+        for (count, lengths) in batch_counts.iter_mut().zip(all_lengths) {
+            self.set_tokens(count, lengths);
+        }
+        let mut batch_counts = batch_counts.into_iter();
+        files
+            .iter()
+            .zip(streamed)
+            .filter_map(|(file, streamed)| {
+                if streamed {
+                    self.skip_read_error(file, self.count_streamed(file))
+                } else {
+                    batch_counts.next()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reader of parts produced lazily one after another
+struct PartsReader<I: Iterator<Item = Vec<u8>>> {
+    parts: I,
+    current: Cursor<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for PartsReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.parts.next() {
+                Some(part) => self.current = Cursor::new(part),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Number of special tokens added by model template to any text
+fn special_tokens_overhead(backend: &dyn CountTokens) -> usize {
+    let with_special = backend.count("", true).unwrap_or(0);
+    let without_special = backend.count("", false).unwrap_or(0);
+    with_special.saturating_sub(without_special)
+}
+
+/// Returns path as string, empty string if path is not valid unicode
+pub fn path_to_string(path: &Path) -> String {
+    path.to_str().unwrap_or("").to_string()
+}
+
+/// Picks number of files in one batch. Batch of each thread takes at most half of
+/// `available_memory` shared by `threads` and at most `MAX_BATCH_BYTES`, every thread
+/// gets at least 4 batches to balance load.
+pub fn auto_chunk_size(
+    files: usize,
+    average_size: u64,
+    threads: usize,
+    available_memory: Option<u64>,
+) -> usize {
+    let Some(available_memory) = available_memory else {
+        return FILE_CHUNK_SIZE;
+    };
+    let threads = threads.max(1);
+    let batch_bytes = (available_memory / 2 / threads as u64).min(MAX_BATCH_BYTES);
+    let by_memory = batch_bytes / (average_size.max(1) * ENCODING_MEMORY_FACTOR);
+    let by_threads = files.div_ceil(threads * 4);
+    (by_memory as usize)
+        .min(by_threads)
+        .clamp(1, MAX_CHUNK_SIZE)
+}
+
+/// Memory available for new allocations without swapping, known only on Linux
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line
+        .trim_start_matches("MemAvailable:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ConfiguredTokenizer;
+    use std::collections::HashMap;
+    use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+    fn test_counter() -> TokenCounter {
+        let tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        TokenCounter::new(tokenizer)
+    }
+
+    #[test]
+    fn test_count_str() {
+        assert_eq!(test_counter().count_str("Hello world, this is a test"), 7);
+    }
+
+    #[test]
+    fn test_count_str_special_tokens() {
+        let counter = test_counter().with_special_tokens(true);
+        assert_eq!(counter.count_str("Hello world"), 4);
+    }
+
+    #[test]
+    fn test_count_batch() {
+        let texts = vec!["Hello world".to_string(), String::new()];
+        assert_eq!(test_counter().count_batch(&texts).unwrap(), vec![2, 0]);
+        let counter = test_counter().with_special_tokens(true);
+        assert_eq!(counter.count_batch(&texts).unwrap(), vec![4, 2]);
+    }
+
+    #[test]
+    fn test_count_concatenated() {
+        let files = vec![PathBuf::from("LICENSE"), PathBuf::from("Cargo.toml")];
+        let joined = format!(
+            "{}\n---\n{}",
+            std::fs::read_to_string("LICENSE").unwrap(),
+            std::fs::read_to_string("Cargo.toml").unwrap()
+        );
+        let counter = test_counter();
+        let expected = counter.count_text(PathBuf::from("all"), &joined);
+        let count = counter.count_concatenated(&files, "\n---\n", PathBuf::from("all"));
+        assert_eq!(count, expected);
+        // Streamed by windows split at whitespace, counts of words and chars are exact
+        let streamed = test_counter().with_stream_threshold(0).count_concatenated(
+            &files,
+            "\n---\n",
+            PathBuf::from("all"),
+        );
+        assert_eq!(streamed.words, expected.words);
+        assert_eq!(streamed.chars, expected.chars);
+    }
+
+    #[test]
+    fn test_count_paths_head_tokens() {
+        let files = vec![PathBuf::from("LICENSE"), PathBuf::from("Cargo.toml")];
+        let full = test_counter().count_paths(&files);
+        let counts = test_counter()
+            .with_head_tokens(Some(100))
+            .count_paths(&files);
+        assert_eq!(counts.len(), 2);
+        assert!(full[0].tokens > 100);
+        assert_eq!(counts[0].tokens, 100);
+        assert!(counts[0].chars < full[0].chars);
+        let counts = test_counter()
+            .with_head_tokens(Some(1_000_000))
+            .count_paths(&files);
+        assert_eq!(counts, full);
+    }
+
+    #[test]
+    fn test_count_lines() {
+        let path =
+            std::env::temp_dir().join(format!("tokencount-lines-{}.jsonl", std::process::id()));
+        std::fs::write(&path, "Hello world\n\nHello world, this is a test\n").unwrap();
+        let counts = test_counter().count_lines(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        let labels: Vec<String> = counts.iter().map(FileCount::get_path_string).collect();
+        let tokens: Vec<usize> = counts.iter().map(|count| count.tokens).collect();
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                format!("{path}:1"),
+                format!("{path}:2"),
+                format!("{path}:3")
+            ]
+        );
+        assert_eq!(tokens, vec![2, 0, 7]);
+    }
+
+    #[test]
+    fn test_count_path_names() {
+        let counter = test_counter();
+        let files = vec![PathBuf::from("missing/notes.txt"), PathBuf::from("LICENSE")];
+        let counts = counter.count_path_names(&files);
+        assert_eq!(counts[0].path, files[0]);
+        assert_eq!(counts[0].tokens, counter.count_str("missing/notes.txt"));
+        assert_eq!(counts[0].chars, 17);
+        assert_eq!(counts[1].tokens, counter.count_str("LICENSE"));
+        assert_eq!(counter.read_errors(), 0);
+    }
+
+    #[test]
+    fn test_count_variables() {
+        let variables = Variables::new(HashMap::from([(
+            "name".to_string(),
+            "hello world".to_string(),
+        )]));
+        let counter = test_counter().with_variables(Some(variables));
+        let count = counter.count_text(PathBuf::from("."), "{{name}} {{ other }}");
+        let plain = test_counter().count_str("hello world {{ other }}");
+        assert_eq!(count.tokens, plain);
+        assert_eq!(count.words, 4);
+        assert_eq!(counter.missing_variables(), vec!["other".to_string()]);
+    }
+
+    #[test]
+    fn test_count_variables_streamed() {
+        let path =
+            std::env::temp_dir().join(format!("tokencount-variables-{}.txt", std::process::id()));
+        // Placeholder is split between the first and the second window
+        let padding = " ".repeat(STREAM_WINDOW_SIZE - 4);
+        std::fs::write(&path, format!("{padding}{{{{ name }}}} {{{{ other }}}}")).unwrap();
+        let variables = Variables::new(HashMap::from([(
+            "name".to_string(),
+            "hello world".to_string(),
+        )]));
+        let counter = test_counter()
+            .with_variables(Some(variables))
+            .with_stream_threshold(0);
+        let counts = counter.count_paths(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        let plain = test_counter().count_str(&format!("{padding}hello world {{{{ other }}}}"));
+        assert_eq!(counts[0].tokens, plain);
+        assert_eq!(counter.missing_variables(), vec!["other".to_string()]);
+    }
+
+    #[test]
+    fn test_count_chat_template() {
+        let template = ChatTemplate::new(
+            "{% for message in messages %}<|{{ message.role }}|>{{ message.content }}{% endfor %}",
+        )
+        .unwrap();
+        let counter = test_counter().with_chat_template(Some(template));
+        let plain = test_counter().count_str("<|user|>Hello world");
+        assert_eq!(counter.count_str("Hello world"), plain);
+        let count = counter.count_text(PathBuf::from("."), "Hello world");
+        assert_eq!(count.tokens, plain);
+        assert_eq!(count.words, 2);
+        let files = vec![PathBuf::from("LICENSE")];
+        let batched = counter.count_paths(&files);
+        let streamed = test_counter()
+            .with_stream_threshold(0)
+            .with_chat_template(Some(
+                ChatTemplate::new("{{ messages[0].content }} end").unwrap(),
+            ))
+            .count_paths(&files);
+        assert!(batched[0].tokens > test_counter().count_paths(&files)[0].tokens);
+        assert_eq!(
+            streamed[0].tokens,
+            test_counter().count_paths(&files)[0].tokens + 1
+        );
+    }
+
+    #[test]
+    fn test_count_paths_interrupted() {
+        let files = vec![PathBuf::from("LICENSE"), PathBuf::from("Cargo.toml")];
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let counter = test_counter()
+            .with_chunk_size(Some(1))
+            .with_interrupt_flag(interrupted.clone());
+        assert_eq!(counter.count_paths(&files).len(), 2);
+        interrupted.store(true, Ordering::Relaxed);
+        assert!(counter.is_interrupted());
+        assert!(counter.count_paths(&files).is_empty());
+    }
+
+    #[test]
+    fn test_count_text_other_backends() {
+        let tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        let counter = test_counter()
+            .with_special_tokens(true)
+            .add_backend(Box::new(tokenizer));
+        let count = counter.count_text(PathBuf::from("."), "Hello world");
+        assert_eq!(count.tokens, 4);
+        assert_eq!(count.other_tokens, vec![4]);
+    }
+
+    #[test]
+    fn test_count_paths_configured_tokenizer() {
+        let dir =
+            std::env::temp_dir().join(format!("tokencount-configured-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: 5,
+                ..TruncationParams::default()
+            }))
+            .unwrap()
+            .with_padding(Some(PaddingParams::default()));
+        let config = dir.join("tokenizer.json");
+        tokenizer.save(&config, false).unwrap();
+        let configured = ConfiguredTokenizer::new(Tokenizer::from_file(&config).unwrap()).unwrap();
+        let streamed = dir.join("streamed.txt");
+        std::fs::write(&streamed, "Hello world ".repeat(STREAM_WINDOW_SIZE / 8)).unwrap();
+        let long = dir.join("long.txt");
+        std::fs::write(&long, "Hello world ".repeat(10)).unwrap();
+        let short = dir.join("short.txt");
+        std::fs::write(&short, "Hello").unwrap();
+        let counter = test_counter()
+            .with_stream_threshold(STREAM_WINDOW_SIZE as u64)
+            .add_backend(Box::new(configured));
+        let counts = counter.count_paths(&[streamed.clone(), long, short.clone()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let configured_tokens: HashMap<PathBuf, Vec<usize>> = counts
+            .into_iter()
+            .map(|count| (count.path, count.other_tokens))
+            .collect();
+        // Streamed file is truncated once, not window by window
+        assert_eq!(configured_tokens[&streamed], vec![5]);
+        // Short file is not padded to the longest file of its batch
+        assert_eq!(configured_tokens[&short], vec![1]);
+    }
+
+    #[test]
+    fn test_count_paths_max_file_size() {
+        let test_data = std::env::current_dir()
+            .unwrap()
+            .join("resources")
+            .join("tokenizer");
+        let files = vec![test_data.join("tokenizer.json")];
+        let counter = test_counter().with_max_file_size(Some(16));
+        assert!(counter.count_paths(&files).is_empty());
+    }
+
+    #[test]
+    fn test_auto_chunk_size() {
+        let gib = 1024 * 1024 * 1024;
+        assert_eq!(auto_chunk_size(100_000, 4096, 8, None), FILE_CHUNK_SIZE);
+        // Small files are limited by MAX_CHUNK_SIZE
+        assert_eq!(
+            auto_chunk_size(100_000, 100, 8, Some(16 * gib)),
+            MAX_CHUNK_SIZE
+        );
+        // Few files are spread between threads
+        assert_eq!(auto_chunk_size(100, 100, 8, Some(16 * gib)), 4);
+        // 1MB files with 16x encoding overhead fit 4 per 64MB batch
+        assert_eq!(auto_chunk_size(100_000, 1024 * 1024, 8, Some(16 * gib)), 4);
+        // Low memory and huge files still make progress
+        assert_eq!(auto_chunk_size(100_000, gib, 8, Some(gib)), 1);
+    }
+
+    #[test]
+    fn test_count_paths_read_error() {
+        let path =
+            std::env::temp_dir().join(format!("tokencount-broken-{}.gz", std::process::id()));
+        // Gzip header followed by invalid stream
+        std::fs::write(&path, [0x1f, 0x8b, 0xff, 0xff, 0xff]).unwrap();
+        let counter = test_counter();
+        let counts = counter.count_paths(&[path.clone(), PathBuf::from("README.md")]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counter.read_errors(), 1);
+        assert_eq!(counter.skipped_files().len(), 1);
+        assert!(counter.skipped_files()[0].reason.starts_with("cannot read"));
+    }
+
+    #[test]
+    fn test_count_paths_binary_file() {
+        let path = std::env::temp_dir().join(format!("tokencount-binary-{}", std::process::id()));
+        std::fs::write(&path, [0u8, 1, 2, 3, 0]).unwrap();
+        let counter = test_counter();
+        let counts = counter.count_paths(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(counts.is_empty());
+        assert_eq!(
+            counter.skipped_files(),
+            vec![SkippedFile {
+                path,
+                reason: BINARY_FILE_REASON.to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_count_paths_file_size() {
+        let path = std::env::temp_dir().join(format!("tokencount-size-{}", std::process::id()));
+        std::fs::write(&path, [0u8, 1, 2, 3, 0]).unwrap();
+        let missing = path.with_extension("missing");
+        let counter = TokenCounter::new(crate::backend::TextUnit::Bytes);
+        let counts = counter.count_paths(&[path.clone(), missing]);
+        std::fs::remove_file(&path).unwrap();
+        // Binary files are counted as stored, unreadable files are skipped
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].tokens, 5);
+        assert_eq!(counter.read_errors(), 1);
+        assert_eq!(counter.count_str("héllo"), 6);
+    }
+
+    #[test]
+    fn test_count_paths_with_cache() {
+        let cache_file =
+            std::env::temp_dir().join(format!("tokencount-cached-{}.json", std::process::id()));
+        let files = vec![PathBuf::from("README.md"), PathBuf::from("Cargo.toml")];
+        let counter = test_counter();
+        let expected = counter.count_paths(&files);
+        let mut cache = CountCache::load(cache_file.clone(), "test".to_string());
+        let counted = counter.count_paths_with_cache(&files, &mut cache, |_| {});
+        cache.save().unwrap();
+        let mut cache = CountCache::load(cache_file.clone(), "test".to_string());
+        let cached = counter.count_paths_with_cache(&files, &mut cache, |_| {});
+        std::fs::remove_file(&cache_file).unwrap();
+        assert_eq!(counted, expected);
+        assert_eq!(cached, expected);
+    }
+
+    #[test]
+    fn test_file_count_from_text() {
+        let count = FileCount::from_text(PathBuf::from("a"), 3, "héllo  wörld\n");
+        assert_eq!(count.words, 2);
+        assert_eq!(count.chars, 13);
+    }
+
+    #[test]
+    fn test_count_files() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new());
+        let counts = test_counter().count_files(files, config).unwrap();
+        assert_eq!(counts.len(), 6);
+        assert!(counts.iter().all(|count| count.tokens == 0));
+    }
+}
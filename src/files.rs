@@ -1,27 +1,152 @@
+use crate::archive::{ArchiveKind, ArchiveMember, member_paths};
+use chardetng::EncodingDetector;
+use clap::ValueEnum;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::MultiGzDecoder;
 use glob;
-use glob::Pattern;
+use glob::{MatchOptions, Pattern, PatternError};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder, WalkState};
+use log::debug;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 use std::fs::File;
-use std::io::{self, Read};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// First bytes of gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Number of bytes read from the beginning of file to decide if it is text
+pub const DEFAULT_SAMPLE_SIZE: usize = 8 * 1024;
+/// Maximum fraction of invalid or non printable bytes in sample of text file
+pub const DEFAULT_BINARY_THRESHOLD: f64 = 0.05;
+/// Ignore file with gitignore syntax read from walked directories
+pub const IGNORE_FILE: &str = ".tokencountignore";
+
+/// Error of resolving files to count
+#[derive(Debug)]
+pub enum FileMatchError {
+    /// Passed path does not exist
+    PathNotFound(String),
+    /// Include or exclude pattern is not valid glob
+    InvalidPattern {
+        pattern: String,
+        source: PatternError,
+    },
+}
+
+impl fmt::Display for FileMatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileMatchError::PathNotFound(path) => write!(f, "Path {path} does not exists"),
+            FileMatchError::InvalidPattern { pattern, source } => {
+                write!(f, "Incorrect format of pattern {pattern}: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileMatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileMatchError::InvalidPattern { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 pub struct FileContent {
     file: PathBuf,
+    /// Set if `file` addresses archive member, e.g. `data.zip:docs/a.txt`
+    member: Option<ArchiveMember>,
+    detect_encoding: bool,
+    normalize_newlines: bool,
+    sample_size: usize,
+    binary_threshold: f64,
 }
 
 impl FileContent {
     pub fn new(file: PathBuf) -> Self {
-        FileContent { file }
+        FileContent {
+            member: ArchiveMember::parse(&file),
+            file,
+            detect_encoding: false,
+            normalize_newlines: false,
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            binary_threshold: DEFAULT_BINARY_THRESHOLD,
+        }
+    }
+
+    /// Convert CRLF line endings to LF in read content
+    pub fn with_newline_normalization(mut self, normalize_newlines: bool) -> Self {
+        self.normalize_newlines = normalize_newlines;
+        self
     }
 
-    fn is_text_file_inner(&self) -> io::Result<bool> {
-        let mut file = File::open(&self.file)?;
-        let mut buffer = [0; 1024];
-        let n = file.read(&mut buffer)?;
-        Ok(std::str::from_utf8(&buffer[..n]).is_ok())
+    /// Number of bytes from the beginning of file used to decide if it is text
+    pub fn with_sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// File is binary if fraction of invalid or non printable bytes in sample exceeds
+    /// `binary_threshold`
+    pub fn with_binary_threshold(mut self, binary_threshold: f64) -> Self {
+        self.binary_threshold = binary_threshold;
+        self
+    }
+
+    /// Detect encoding of non UTF-8 files and decode them instead of skipping
+    pub fn with_encoding_detection(mut self, detect_encoding: bool) -> Self {
+        self.detect_encoding = detect_encoding;
+        self
+    }
+
+    /// Opens file for reading, gzip compressed files are decompressed transparently.
+    /// Archive members are read into memory.
+    fn open(&self) -> io::Result<Box<dyn Read>> {
+        let reader: Box<dyn Read> = match &self.member {
+            Some(member) => Box::new(Cursor::new(member.read()?)),
+            None => Box::new(File::open(&self.file)?),
+        };
+        let mut reader = BufReader::new(reader);
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            Ok(Box::new(MultiGzDecoder::new(reader)))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    fn read_sample(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(self.sample_size);
+        self.open()?
+            .take(self.sample_size as u64)
+            .read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.open()?.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Same as `is_text_file`, but returns read error instead of printing it
+    pub fn check_text_file(&self) -> io::Result<bool> {
+        let sample = self.read_sample()?;
+        // NUL bytes mean binary file, unless it is decoded as UTF-16 below
+        if !sample.contains(&0) && non_text_ratio(&sample) <= self.binary_threshold {
+            return Ok(true);
+        }
+        Ok(self.detect_encoding && decode_sample(&sample, detect_encoding(&sample)).is_some())
     }
 
     pub fn is_text_file(&self) -> bool {
-        match self.is_text_file_inner() {
+        match self.check_text_file() {
             Ok(is_text) => is_text,
             Err(e) => {
                 eprintln!("Error reading file {}: {}", self.file.display(), e);
@@ -30,14 +155,284 @@ impl FileContent {
         }
     }
 
-    pub fn read_content(&self) -> String {
-        std::fs::read_to_string(&self.file)
-            .unwrap_or_else(|_| panic!("Cannot read file: {}", self.file.display()))
+    /// Returns encoding of the file, UTF-8 if encoding detection is disabled
+    pub fn encoding(&self) -> &'static Encoding {
+        if !self.detect_encoding {
+            return UTF_8;
+        }
+        match self.read_sample() {
+            Ok(sample) if std::str::from_utf8(&sample).is_err() => detect_encoding(&sample),
+            _ => UTF_8,
+        }
+    }
+
+    pub fn read_content(&self) -> io::Result<String> {
+        let bytes = self.read_bytes()?;
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) if self.detect_encoding => {
+                let bytes = e.into_bytes();
+                let (text, _, _) = detect_encoding(&bytes).decode(&bytes);
+                text.into_owned()
+            }
+            // Text file with few invalid bytes allowed by binary threshold
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        };
+        if self.normalize_newlines {
+            return Ok(normalize_newlines(text));
+        }
+        Ok(text)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file
     }
 
     pub fn get_path_string(&self) -> String {
         self.file.to_str().unwrap_or("").to_string()
     }
+
+    /// Hash of uncompressed content
+    pub fn content_hash(&self) -> io::Result<blake3::Hash> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(self.open()?)?;
+        Ok(hasher.finalize())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.stored_size().unwrap_or(0)
+    }
+
+    /// Size of file on disk or uncompressed size of archive member, content is not read
+    pub fn stored_size(&self) -> io::Result<u64> {
+        match &self.member {
+            Some(member) => member.size(),
+            None => std::fs::metadata(&self.file).map(|metadata| metadata.len()),
+        }
+    }
+
+    /// Reads file content by windows of approximately `window_size` bytes
+    pub fn read_windows(&self, window_size: usize) -> io::Result<ContentWindows<Box<dyn Read>>> {
+        Ok(ContentWindows::new(self.open()?, window_size)
+            .with_newline_normalization(self.normalize_newlines))
+    }
+}
+
+/// Guesses encoding by byte order mark, otherwise by content
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+}
+
+/// Decodes beginning of the file. Returns `None` if sample is malformed for the encoding or
+/// decoded text contains control characters, which is typical for binary files.
+fn decode_sample(sample: &[u8], encoding: &'static Encoding) -> Option<String> {
+    let mut decoder = encoding.new_decoder();
+    let mut text = String::with_capacity(sample.len() * 3);
+    let (result, _) = decoder.decode_to_string_without_replacement(sample, &mut text, false);
+    if matches!(result, encoding_rs::DecoderResult::Malformed(_, _)) {
+        return None;
+    }
+    let is_binary = text.chars().any(|c| !is_text_char(c));
+    if is_binary { None } else { Some(text) }
+}
+
+/// Converts CRLF line endings to LF
+pub fn normalize_newlines(text: String) -> String {
+    if text.contains("\r\n") {
+        text.replace("\r\n", "\n")
+    } else {
+        text
+    }
+}
+
+fn is_text_char(c: char) -> bool {
+    !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\u{feff}')
+}
+
+/// Fraction of bytes which are not valid UTF-8 or encode control characters.
+/// Incomplete character at the end is ignored, it can be cut off by sampling.
+fn non_text_ratio(sample: &[u8]) -> f64 {
+    let sample = &sample[..complete_prefix_len(sample)];
+    if sample.is_empty() {
+        return 0.0;
+    }
+    let non_text: usize = sample
+        .utf8_chunks()
+        .map(|chunk| {
+            let control: usize = chunk
+                .valid()
+                .chars()
+                .filter(|c| !is_text_char(*c))
+                .map(char::len_utf8)
+                .sum();
+            control + chunk.invalid().len()
+        })
+        .sum();
+    non_text as f64 / sample.len() as f64
+}
+
+/// Length of bytes without incomplete multibyte UTF-8 character at the end
+fn complete_prefix_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(4) {
+        let position = bytes.len() - back;
+        let byte = bytes[position];
+        // Skip continuation bytes until the leading byte of the last character
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let char_len = match byte {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if char_len > back {
+            position
+        } else {
+            bytes.len()
+        };
+    }
+    bytes.len()
+}
+
+/// Iterator over text windows of a reader.
+/// Windows are split after the last whitespace, so neither multibyte character nor a word is
+/// split between two windows. Not consumed tail of the window is prepended to the next one.
+pub struct ContentWindows<R: Read> {
+    reader: R,
+    window_size: usize,
+    pending: Vec<u8>,
+    eof: bool,
+    normalize_newlines: bool,
+}
+
+impl<R: Read> ContentWindows<R> {
+    pub fn new(reader: R, window_size: usize) -> Self {
+        ContentWindows {
+            reader,
+            window_size,
+            pending: Vec::new(),
+            eof: false,
+            normalize_newlines: false,
+        }
+    }
+
+    /// Convert CRLF line endings to LF in windows
+    pub fn with_newline_normalization(mut self, normalize_newlines: bool) -> Self {
+        self.normalize_newlines = normalize_newlines;
+        self
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buffer = vec![0; self.window_size];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let n = self.reader.read(&mut buffer[filled..])?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            filled += n;
+        }
+        self.pending.extend_from_slice(&buffer[..filled]);
+        Ok(())
+    }
+
+    fn split_position(&self) -> usize {
+        if self.eof {
+            return self.pending.len();
+        }
+        // Ascii bytes are never part of multibyte sequence, so this is always a char boundary.
+        // CRLF is never split, so it can be normalized inside the window.
+        if let Some(pos) = self
+            .pending
+            .iter()
+            .rposition(|b| b.is_ascii_whitespace() && *b != b'\r')
+        {
+            return pos + 1;
+        }
+        let position = complete_prefix_len(&self.pending);
+        if self.pending[..position].ends_with(b"\r") {
+            position - 1
+        } else {
+            position
+        }
+    }
+}
+
+impl<R: Read> Iterator for ContentWindows<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.eof && self.pending.is_empty() {
+            return None;
+        }
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.pending.is_empty() {
+            return None;
+        }
+        let position = self.split_position();
+        let tail = self.pending.split_off(position);
+        let window = std::mem::replace(&mut self.pending, tail);
+        // Invalid bytes are replaced, files with them pass only within binary threshold
+        let window = String::from_utf8_lossy(&window).into_owned();
+        if self.normalize_newlines {
+            return Some(Ok(normalize_newlines(window)));
+        }
+        Some(Ok(window))
+    }
+}
+
+/// Order of files returned by `get_matched_files`
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileOrder {
+    /// Lexical order of paths
+    Name,
+    /// Files with fewer path components first, then lexical order
+    #[default]
+    Depth,
+    /// Order in which files are found, files are not sorted. Directories are walked in
+    /// parallel, so order may change between runs
+    None,
+    /// Oldest modification time first, each file is stat'ed
+    Mtime,
+    /// Smallest size first, each file is stat'ed
+    Size,
+}
+
+impl FileOrder {
+    /// Sorts files, files with equal modification time or size are ordered by path.
+    /// Metadata of files which cannot be stat'ed, e.g. archive members, sorts first.
+    pub fn sort(self, files: &mut [PathBuf]) {
+        match self {
+            FileOrder::Name => files.sort(),
+            FileOrder::Depth => files.sort_by(|path1, path2| {
+                let component_num1 = path1.components().count();
+                let component_num2 = path2.components().count();
+                if component_num1 == component_num2 {
+                    path1.cmp(path2)
+                } else {
+                    component_num1.cmp(&component_num2)
+                }
+            }),
+            FileOrder::None => {}
+            FileOrder::Mtime => files.par_sort_by_cached_key(|path| {
+                let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified());
+                (modified.ok(), path.clone())
+            }),
+            FileOrder::Size => files.par_sort_by_cached_key(|path| {
+                let size = std::fs::metadata(path).map(|metadata| metadata.len());
+                (size.ok(), path.clone())
+            }),
+        }
+    }
 }
 
 pub struct FileMatchConfig {
@@ -46,6 +441,14 @@ pub struct FileMatchConfig {
     include: Vec<String>,
     exclude: Vec<String>,
     exclude_dir: Vec<String>,
+    gitignore: bool,
+    max_depth: Option<usize>,
+    ignore_case: bool,
+    hidden: bool,
+    invert: bool,
+    ignore_file: bool,
+    order: FileOrder,
+    extensions: Vec<String>,
 }
 
 impl FileMatchConfig {
@@ -62,61 +465,505 @@ impl FileMatchConfig {
             include,
             exclude,
             exclude_dir,
+            gitignore: false,
+            max_depth: None,
+            ignore_case: false,
+            hidden: false,
+            invert: false,
+            ignore_file: false,
+            order: FileOrder::Depth,
+            extensions: Vec::new(),
         }
     }
+
+    /// Skip files ignored by `.gitignore` files found during recursive walk
+    pub fn with_gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    /// Descend at most `max_depth` directories below the walked root,
+    /// 0 means only files directly inside the root
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Match include, exclude and exclude-dir patterns case insensitively
+    pub fn with_ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Include files and directories whose name starts with `.` found during recursive walk
+    pub fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Select files not selected by include and exclude patterns. Directories are still
+    /// skipped by exclude-dir patterns, but not by exclude patterns.
+    pub fn with_invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// Skip entries matched by `.tokencountignore` files found during recursive walk
+    pub fn with_ignore_file(mut self, ignore_file: bool) -> Self {
+        self.ignore_file = ignore_file;
+        self
+    }
+
+    /// Order of files returned by `get_matched_files`, `matched_files_iter` ignores it
+    pub fn with_order(mut self, order: FileOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Include only files with one of extensions, e.g. `rs` or `.md`, checked before
+    /// patterns. Empty list includes all extensions
+    pub fn with_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = extensions;
+        self
+    }
 }
 
-fn vec_pattern_to_glob(pattern_vec: Vec<String>) -> Vec<Pattern> {
+fn vec_pattern_to_glob(pattern_vec: Vec<String>) -> Result<Vec<Pattern>, FileMatchError> {
     pattern_vec
-        .into_iter()
-        .map(|pattern| Pattern::new(&pattern))
-        .map(|res| res.expect("Incorrect format of pattern "))
+        .iter()
+        .flat_map(|pattern| expand_braces(pattern))
+        .map(|pattern| {
+            Pattern::new(&pattern)
+                .map_err(|source| FileMatchError::InvalidPattern { pattern, source })
+        })
         .collect()
 }
 
-fn matches(pattern_vec: &[Pattern], path: &Path) -> bool {
+/// Expands shell like braces, e.g. `*.{js,ts}` into `*.js` and `*.ts`. Nested braces are
+/// expanded too, braces without a comma or closing brace and braces inside `[...]` are kept
+/// literally. Duplicate expansions are removed.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut in_class = false;
+    for (open, byte) in pattern.bytes().enumerate() {
+        match byte {
+            b'[' if !in_class => in_class = true,
+            b']' if in_class => in_class = false,
+            b'{' if !in_class => {
+                let Some((close, alternatives)) = brace_alternatives(pattern, open) else {
+                    continue;
+                };
+                let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+                let mut expanded: Vec<String> = Vec::new();
+                for alternative in alternatives {
+                    for pattern in expand_braces(&format!("{prefix}{alternative}{suffix}")) {
+                        if !expanded.contains(&pattern) {
+                            expanded.push(pattern);
+                        }
+                    }
+                }
+                return expanded;
+            }
+            _ => {}
+        }
+    }
+    vec![pattern.to_string()]
+}
+
+/// Finds closing brace of brace opened at `open` and splits its content at top level commas.
+/// Returns `None` if brace is not closed or has no comma.
+fn brace_alternatives(pattern: &str, open: usize) -> Option<(usize, Vec<&str>)> {
+    let mut depth = 0;
+    let mut in_class = false;
+    let mut start = open + 1;
+    let mut alternatives = Vec::new();
+    for (index, byte) in pattern.bytes().enumerate().skip(open) {
+        match byte {
+            b'[' if !in_class => in_class = true,
+            b']' if in_class => in_class = false,
+            _ if in_class => {}
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    if alternatives.is_empty() {
+                        return None;
+                    }
+                    alternatives.push(&pattern[start..index]);
+                    return Some((index, alternatives));
+                }
+            }
+            b',' if depth == 1 => {
+                alternatives.push(&pattern[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Checks if any pattern matches either file name or path relative to the root
+fn matches(pattern_vec: &[Pattern], options: MatchOptions, root: &Path, path: &Path) -> bool {
     if pattern_vec.is_empty() {
         return false;
     }
-    let file_name = path.file_name().unwrap().to_str().unwrap();
-    pattern_vec.iter().any(|pattern| pattern.matches(file_name))
+    let relative_path = match path.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => path,
+        Ok(relative) => relative,
+        Err(_) => path,
+    };
+    let file_name = path.file_name().and_then(|name| name.to_str());
+    pattern_vec.iter().any(|pattern| {
+        file_name.is_some_and(|name| pattern.matches_with(name, options))
+            || pattern.matches_path_with(relative_path, options)
+    })
 }
 
+/// Shared between walker threads, so it must stay `Sync`
+#[derive(Clone)]
 struct PathMatcher {
     include_pattern: Vec<Pattern>,
     exclude_pattern: Vec<Pattern>,
     exclude_dir_pattern: Vec<Pattern>,
+    options: MatchOptions,
+    hidden: bool,
+    invert: bool,
+    extensions: Vec<String>,
 }
 
 impl PathMatcher {
-    pub fn new(include: Vec<String>, exclude: Vec<String>, exclude_dir: Vec<String>) -> Self {
-        PathMatcher {
-            include_pattern: vec_pattern_to_glob(include),
-            exclude_pattern: vec_pattern_to_glob(exclude),
-            exclude_dir_pattern: vec_pattern_to_glob(exclude_dir),
+    pub fn new(
+        include: Vec<String>,
+        exclude: Vec<String>,
+        exclude_dir: Vec<String>,
+        ignore_case: bool,
+        hidden: bool,
+        invert: bool,
+    ) -> Result<Self, FileMatchError> {
+        Ok(PathMatcher {
+            include_pattern: vec_pattern_to_glob(include)?,
+            exclude_pattern: vec_pattern_to_glob(exclude)?,
+            exclude_dir_pattern: vec_pattern_to_glob(exclude_dir)?,
+            options: MatchOptions {
+                case_sensitive: !ignore_case,
+                require_literal_separator: true,
+                ..MatchOptions::new()
+            },
+            hidden,
+            invert,
+            extensions: Vec::new(),
+        })
+    }
+
+    fn with_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = extensions
+            .iter()
+            .map(|extension| extension.trim().trim_start_matches('.').to_string())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+        self
+    }
+
+    /// Checks extension of file name only, so files of other types are skipped without
+    /// opening them
+    fn has_allowed_extension(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
         }
+        let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+            return false;
+        };
+        self.extensions.iter().any(|allowed| {
+            if self.options.case_sensitive {
+                allowed == extension
+            } else {
+                allowed.eq_ignore_ascii_case(extension)
+            }
+        })
+    }
+
+    fn matches(&self, pattern_vec: &[Pattern], root: &Path, path: &Path) -> bool {
+        matches(pattern_vec, self.options, root, path)
     }
 
     /// Checks if the file should be included based on the patterns.
     /// Returns true if there are no patterns or the file matches the include pattern and
     /// does not match the exclude pattern.
-    fn should_file_be_included(&self, path: &Path) -> bool {
-        if self.include_pattern.is_empty() && self.exclude_pattern.is_empty() {
-            if path.is_file() {
-                true // No patterns, include all files
-            } else {
-                !matches(&self.exclude_dir_pattern, path)
+    /// Patterns are matched against file name and path relative to `root`.
+    /// Hidden entries below `root` are skipped unless `hidden` is set.
+    /// With `invert` the decision for files is flipped and exclude patterns do not skip
+    /// directories, so excluded files inside them can be found.
+    fn should_file_be_included(&self, root: &Path, path: &Path) -> bool {
+        if !self.hidden && is_hidden(root, path) {
+            debug!("Skipping hidden {}", path.display());
+            return false;
+        }
+        if path.is_file() {
+            if !self.has_allowed_extension(path) {
+                debug!("Skipping file {}, extension is not allowed", path.display());
+                return false;
             }
-        } else if !self.include_pattern.is_empty() && path.is_file() {
-            matches(&self.include_pattern, path)
-        } else if path.is_file() {
-            !matches(&self.exclude_pattern, path)
-        } else {
-            !matches(&self.exclude_pattern, path) && !matches(&self.exclude_dir_pattern, path)
+            // No include patterns, include all files not matched by exclude patterns
+            let included = (self.include_pattern.is_empty()
+                || self.matches(&self.include_pattern, root, path))
+                && !self.matches(&self.exclude_pattern, root, path);
+            if included == self.invert {
+                debug!(
+                    "Skipping file {}, not selected by include and exclude patterns",
+                    path.display()
+                );
+            }
+            return included != self.invert;
+        }
+        if self.matches(&self.exclude_dir_pattern, root, path) {
+            debug!(
+                "Skipping directory {}, matched by exclude-dir pattern",
+                path.display()
+            );
+            return false;
+        }
+        if !self.invert && self.matches(&self.exclude_pattern, root, path) {
+            debug!(
+                "Skipping directory {}, matched by exclude pattern",
+                path.display()
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Checks if name of entry found below `root` starts with `.`. The root itself and paths
+/// passed as arguments, which are checked with empty root, are never hidden.
+fn is_hidden(root: &Path, path: &Path) -> bool {
+    !root.as_os_str().is_empty()
+        && path != root
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Range of bytes of a file, open `end` reads to the end of file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// Parses `START:END` byte range, bounds are sizes like `4KB` and can be omitted,
+/// e.g. `:4096` or `1KB:`
+pub fn parse_byte_range(range: &str) -> Result<ByteRange, String> {
+    let (start, end) = range
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid byte range {range}, expected START:END"))?;
+    let bound = |bound: &str| {
+        (!bound.trim().is_empty())
+            .then(|| parse_size(bound))
+            .transpose()
+    };
+    let start = bound(start)?.unwrap_or(0);
+    let end = bound(end)?;
+    if end.is_some_and(|end| end < start) {
+        return Err(format!("Invalid byte range {range}, end is before start"));
+    }
+    Ok(ByteRange { start, end })
+}
+
+/// Reads range of bytes of file as UTF-8 text. Range is shrunk to whole characters, so
+/// characters split by its bounds are not counted
+pub fn read_byte_range(path: &Path, range: ByteRange) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut bytes = Vec::new();
+    match range.end {
+        Some(end) => file.take(end - range.start).read_to_end(&mut bytes)?,
+        None => file.read_to_end(&mut bytes)?,
+    };
+    // Continuation bytes of a character started before the range
+    let start = bytes
+        .iter()
+        .take(3)
+        .take_while(|byte| *byte & 0xc0 == 0x80)
+        .count();
+    bytes.drain(..start);
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        // Character is cut by the end of range
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid);
+            Ok(String::from_utf8(bytes).expect("Valid prefix of UTF-8"))
         }
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
     }
 }
 
+/// Parses human friendly size like `500KB`, `2MB` or `1GB` into bytes.
+/// Units are binary and case insensitive, number without unit is bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {size}, expected number with optional unit"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "Unknown size unit {unit}, expected B, KB, MB, GB or TB"
+            ));
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {size} is too large"))
+}
+
+/// Removes files with the same content as an earlier file, returns kept files and number of
+/// removed duplicates. Only files with the same size as another file are read and hashed,
+/// unreadable files are kept.
+pub fn dedup_files(files: Vec<PathBuf>) -> (Vec<PathBuf>, usize) {
+    let sizes: Vec<u64> = files
+        .par_iter()
+        .map(|file| FileContent::new(file.clone()).size())
+        .collect();
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for size in &sizes {
+        *size_counts.entry(*size).or_default() += 1;
+    }
+    let hashes: Vec<Option<blake3::Hash>> = files
+        .par_iter()
+        .zip(&sizes)
+        .map(|(file, size)| {
+            (size_counts[size] > 1)
+                .then(|| FileContent::new(file.clone()).content_hash().ok())
+                .flatten()
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    let kept: Vec<PathBuf> = files
+        .into_iter()
+        .zip(hashes)
+        .filter(|(_, hash)| hash.is_none_or(|hash| seen.insert(hash)))
+        .map(|(file, _)| file)
+        .collect();
+    let duplicates = sizes.len() - kept.len();
+    (kept, duplicates)
+}
+
+/// Common ancestor directory of `paths`, files contribute their parent directory.
+/// Paths are made absolute without resolving symlinks.
+pub fn common_ancestor(paths: &[PathBuf]) -> Option<PathBuf> {
+    let mut ancestor: Option<PathBuf> = None;
+    for path in paths {
+        let path = std::path::absolute(path).ok()?;
+        let dir = if path.is_dir() {
+            path.as_path()
+        } else {
+            path.parent()?
+        };
+        ancestor = Some(match ancestor {
+            None => dir.to_path_buf(),
+            Some(ancestor) => ancestor
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    ancestor
+}
+
+/// Path relative to `root`, with `..` for parts of `root` which are not ancestors of `path`.
+/// Path is returned unchanged if either path cannot be made absolute.
+pub fn relative_path(path: &Path, root: &Path) -> PathBuf {
+    let (Ok(absolute_path), Ok(absolute_root)) =
+        (std::path::absolute(path), std::path::absolute(root))
+    else {
+        return path.to_path_buf();
+    };
+    let path_components: Vec<_> = absolute_path.components().collect();
+    let root_components: Vec<_> = absolute_root.components().collect();
+    let common = path_components
+        .iter()
+        .zip(&root_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        // Different prefixes on Windows, e.g. other drive
+        return path.to_path_buf();
+    }
+    let relative: PathBuf =
+        std::iter::repeat_n(Component::ParentDir, root_components.len() - common)
+            .chain(path_components[common..].iter().copied())
+            .collect();
+    if relative.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        relative
+    }
+}
+
+/// Paths of files changed in the git repository of the current directory, relative to it.
+/// Without `reference` staged files are listed, otherwise files of the working tree which
+/// differ from `reference`. Only files matching `pathspecs` are listed if any is given.
+/// Deleted files and files outside of the current directory are not listed.
+pub fn git_changed_files(
+    reference: Option<&str>,
+    pathspecs: &[String],
+) -> crate::Result<Vec<String>> {
+    let mut command = Command::new("git");
+    command.args(["diff", "--name-only", "--relative", "--diff-filter=d", "-z"]);
+    match reference {
+        Some(reference) => command.arg(reference),
+        None => command.arg("--cached"),
+    };
+    command.arg("--").args(pathspecs);
+    let output = command
+        .output()
+        .map_err(|e| format!("Cannot run git: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git diff failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(parse_path_list(
+        &String::from_utf8_lossy(&output.stdout),
+        true,
+    ))
+}
+
+/// Splits list of paths separated by newlines or by NUL bytes if `null` is set.
+/// Empty entries are skipped.
+pub fn parse_path_list(content: &str, null: bool) -> Vec<String> {
+    let separator = if null { '\0' } else { '\n' };
+    content
+        .split(separator)
+        .map(|path| {
+            if null {
+                path
+            } else {
+                path.trim_end_matches('\r')
+            }
+        })
+        .filter(|path| !path.is_empty())
+        .map(|path| path.to_string())
+        .collect()
+}
+
 fn get_folder_content(path: &Path) -> Vec<PathBuf> {
     let files: Vec<PathBuf> = std::fs::read_dir(path)
         .into_iter()
@@ -128,80 +975,536 @@ fn get_folder_content(path: &Path) -> Vec<PathBuf> {
     files
 }
 
+/// Matchers of `.tokencountignore` files of directories from walk root to current directory
+#[derive(Clone, Default)]
+struct IgnoreStack(Vec<Arc<Gitignore>>);
+
+impl IgnoreStack {
+    /// Adds matcher of ignore file of `folder` if it has one, invalid patterns are skipped
+    fn enter(&self, folder: &Path) -> Self {
+        let ignore_file = folder.join(IGNORE_FILE);
+        if !ignore_file.is_file() {
+            return self.clone();
+        }
+        let mut builder = GitignoreBuilder::new(folder);
+        builder.add(ignore_file);
+        let mut stack = self.clone();
+        if let Ok(gitignore) = builder.build() {
+            stack.0.push(Arc::new(gitignore));
+        }
+        stack
+    }
+
+    /// Ignore file of the deepest directory with matching pattern decides, so negated
+    /// patterns can include paths ignored by parent directories
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.0.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => {
+                    debug!("Skipping {}, matched by {IGNORE_FILE}", path.display());
+                    return true;
+                }
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// Walks directory breadth first, directories of the same depth are read in parallel
+fn walk_folder(
+    path: &Path,
+    path_matcher: &PathMatcher,
+    include_symlinks: bool,
+    max_depth: Option<usize>,
+    ignore_file: bool,
+) -> Vec<PathBuf> {
+    let mut found_files = Vec::new();
+    if !path_matcher.should_file_be_included(path, path) {
+        return found_files;
+    }
+    let mut folders = vec![(path.to_path_buf(), IgnoreStack::default())];
+    let mut depth = 0;
+    while !folders.is_empty() {
+        let descend = max_depth.is_none_or(|max_depth| depth < max_depth);
+        let (files, subfolders): (Vec<Vec<PathBuf>>, Vec<Vec<_>>) = folders
+            .par_iter()
+            .map(|(folder, ignores)| {
+                let ignores = if ignore_file {
+                    ignores.enter(folder)
+                } else {
+                    ignores.clone()
+                };
+                let mut files = Vec::new();
+                let mut subfolders = Vec::new();
+                for entry in get_folder_content(folder) {
+                    if entry.is_symlink() && !include_symlinks {
+                        continue;
+                    }
+                    let is_dir = entry.is_dir();
+                    if ignores.is_ignored(&entry, is_dir) {
+                        continue;
+                    }
+                    if is_dir {
+                        if descend && path_matcher.should_file_be_included(path, &entry) {
+                            subfolders.push((entry, ignores.clone()));
+                        }
+                    } else if path_matcher.should_file_be_included(path, &entry) {
+                        files.push(entry);
+                    }
+                }
+                (files, subfolders)
+            })
+            .unzip();
+        found_files.extend(files.into_iter().flatten());
+        folders = subfolders.into_iter().flatten().collect();
+        depth += 1;
+    }
+    found_files
+}
+
+/// Walker which skips entries ignored by `.gitignore` files and, with `ignore_file`, by
+/// `.tokencountignore` files
+fn gitignore_walk_builder(
+    path: &Path,
+    path_matcher: &PathMatcher,
+    include_symlinks: bool,
+    max_depth: Option<usize>,
+    ignore_file: bool,
+) -> WalkBuilder {
+    let matcher = path_matcher.clone();
+    let root = path.to_path_buf();
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .standard_filters(false)
+        .git_ignore(true)
+        .git_exclude(true)
+        .require_git(false)
+        .follow_links(include_symlinks)
+        // Walker counts the root itself as depth 0
+        .max_depth(max_depth.map(|max_depth| max_depth + 1))
+        .filter_entry(move |entry| {
+            (include_symlinks || !entry.path_is_symlink())
+                && matcher.should_file_be_included(&root, entry.path())
+        });
+    if ignore_file {
+        builder.add_custom_ignore_filename(IGNORE_FILE);
+    }
+    builder
+}
+
+/// Same as `walk_folder`, but skips entries ignored by `.gitignore` files
+fn walk_folder_gitignore(
+    path: &Path,
+    path_matcher: &PathMatcher,
+    include_symlinks: bool,
+    max_depth: Option<usize>,
+    ignore_file: bool,
+) -> Vec<PathBuf> {
+    let found_files = Mutex::new(Vec::new());
+    gitignore_walk_builder(path, path_matcher, include_symlinks, max_depth, ignore_file)
+        .threads(rayon::current_num_threads())
+        .build_parallel()
+        .run(|| {
+            let found_files = &found_files;
+            Box::new(move |entry| {
+                if let Ok(entry) = entry
+                    && !entry.path().is_dir()
+                {
+                    found_files
+                        .lock()
+                        .expect("Walker thread panicked")
+                        .push(entry.into_path());
+                }
+                WalkState::Continue
+            })
+        });
+    found_files.into_inner().expect("Walker thread panicked")
+}
+
 pub fn get_matched_files(
     initial_files: Vec<String>,
     file_match_config: FileMatchConfig,
-) -> Vec<PathBuf> {
+) -> Result<Vec<PathBuf>, FileMatchError> {
     let path_matcher = PathMatcher::new(
         file_match_config.include,
         file_match_config.exclude,
         file_match_config.exclude_dir,
-    );
-    let mut result: Vec<PathBuf> = initial_files
-        .into_iter()
-        .flat_map(|file| {
-            if !std::fs::exists(&file).unwrap_or(false) {
-                panic!("Path {} does not exists", &file)
+        file_match_config.ignore_case,
+        file_match_config.hidden,
+        file_match_config.invert,
+    )?
+    .with_extensions(file_match_config.extensions);
+    let mut result: Vec<PathBuf> = Vec::new();
+    for file in initial_files {
+        if !std::fs::exists(&file).unwrap_or(false) {
+            // Member or directory inside archive, e.g. `data.zip:docs/a.txt`
+            let members = ArchiveMember::parse(Path::new(&file))
+                .and_then(|member| member_paths(&member.archive, &member.name).ok())
+                .filter(|members| !members.is_empty())
+                .ok_or_else(|| FileMatchError::PathNotFound(file.clone()))?;
+            result.extend(members);
+            continue;
+        }
+        let path = Path::new(&file);
+        if path.is_file() {
+            if path_matcher.should_file_be_included(Path::new(""), path) {
+                result.push(path.to_path_buf());
             }
-            let path = Path::new(&file);
-            if path.is_file() {
-                return if path_matcher.should_file_be_included(path) {
-                    vec![path.to_path_buf()]
-                } else {
-                    Vec::new()
+            continue;
+        }
+        // Without recursive flag only files directly inside the directory are counted
+        let max_depth = if file_match_config.recursive {
+            file_match_config.max_depth
+        } else {
+            Some(0)
+        };
+        let found_files = if file_match_config.gitignore {
+            walk_folder_gitignore(
+                path,
+                &path_matcher,
+                file_match_config.include_symlinks,
+                max_depth,
+                file_match_config.ignore_file,
+            )
+        } else {
+            walk_folder(
+                path,
+                &path_matcher,
+                file_match_config.include_symlinks,
+                max_depth,
+                file_match_config.ignore_file,
+            )
+        };
+        result.extend(found_files);
+    }
+    file_match_config.order.sort(&mut result);
+    Ok(result.into_iter().flat_map(expand_archive).collect())
+}
+
+/// Same as `get_matched_files`, but directories are read lazily one at a time and files are
+/// returned in walk order without sorting, so paths of huge trees are not collected at once.
+/// Invalid paths are returned as errors when they are reached.
+pub fn matched_files_iter(
+    initial_files: Vec<String>,
+    file_match_config: FileMatchConfig,
+) -> Result<impl Iterator<Item = Result<PathBuf, FileMatchError>>, FileMatchError> {
+    let path_matcher = PathMatcher::new(
+        file_match_config.include,
+        file_match_config.exclude,
+        file_match_config.exclude_dir,
+        file_match_config.ignore_case,
+        file_match_config.hidden,
+        file_match_config.invert,
+    )?
+    .with_extensions(file_match_config.extensions);
+    let include_symlinks = file_match_config.include_symlinks;
+    let gitignore = file_match_config.gitignore;
+    let max_depth = if file_match_config.recursive {
+        file_match_config.max_depth
+    } else {
+        Some(0)
+    };
+    let ignore_file = file_match_config.ignore_file;
+    Ok(initial_files.into_iter().flat_map(
+        move |file| -> Box<dyn Iterator<Item = Result<PathBuf, FileMatchError>>> {
+            if !std::fs::exists(&file).unwrap_or(false) {
+                let members = ArchiveMember::parse(Path::new(&file))
+                    .and_then(|member| member_paths(&member.archive, &member.name).ok())
+                    .filter(|members| !members.is_empty())
+                    .ok_or(FileMatchError::PathNotFound(file));
+                return match members {
+                    Ok(members) => Box::new(members.into_iter().map(Ok)),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
                 };
             }
-            if path.is_dir() && !file_match_config.recursive {
-                panic!("Path {file} is directory and recursive flag is false");
-            }
-            let mut folder_stack: Vec<PathBuf> = Vec::new();
-            folder_stack.push(path.to_path_buf());
-            let mut found_files = Vec::new();
+            let path = PathBuf::from(&file);
+            let found_files: Box<dyn Iterator<Item = PathBuf>> = if path.is_file() {
+                let included = path_matcher.should_file_be_included(Path::new(""), &path);
+                Box::new(included.then_some(path).into_iter())
+            } else if gitignore {
+                let walk = gitignore_walk_builder(
+                    &path,
+                    &path_matcher,
+                    include_symlinks,
+                    max_depth,
+                    ignore_file,
+                )
+                .build();
+                Box::new(
+                    walk.filter_map(|entry| entry.ok())
+                        .map(|entry| entry.into_path())
+                        .filter(|path| !path.is_dir()),
+                )
+            } else {
+                Box::new(walk_folder_lazy(
+                    path,
+                    path_matcher.clone(),
+                    include_symlinks,
+                    max_depth,
+                    ignore_file,
+                ))
+            };
+            Box::new(found_files.flat_map(expand_archive).map(Ok))
+        },
+    ))
+}
 
-            while let Some(top_folder) = folder_stack.pop() {
-                if !path_matcher.should_file_be_included(&top_folder) {
+/// Same as `walk_folder`, but folders are read one by one when files of previous ones
+/// are consumed
+fn walk_folder_lazy(
+    root: PathBuf,
+    path_matcher: PathMatcher,
+    include_symlinks: bool,
+    max_depth: Option<usize>,
+    ignore_file: bool,
+) -> impl Iterator<Item = PathBuf> {
+    let mut folders = VecDeque::new();
+    if path_matcher.should_file_be_included(&root, &root) {
+        folders.push_back((root.clone(), 0, IgnoreStack::default()));
+    }
+    let mut files = VecDeque::new();
+    std::iter::from_fn(move || {
+        loop {
+            if let Some(file) = files.pop_front() {
+                return Some(file);
+            }
+            let (folder, depth, ignores) = folders.pop_front()?;
+            let ignores = if ignore_file {
+                ignores.enter(&folder)
+            } else {
+                ignores
+            };
+            let descend = max_depth.is_none_or(|max_depth| depth < max_depth);
+            for entry in get_folder_content(&folder) {
+                if entry.is_symlink() && !include_symlinks {
                     continue;
                 }
-                let folder_content = get_folder_content(&top_folder);
-                for entry in folder_content {
-                    if entry.is_symlink() && !file_match_config.include_symlinks {
-                        continue;
-                    }
-                    if entry.is_dir() {
-                        if path_matcher.should_file_be_included(&entry) {
-                            folder_stack.push(entry);
-                        }
-                    } else if path_matcher.should_file_be_included(&entry) {
-                        found_files.push(entry);
+                let is_dir = entry.is_dir();
+                if ignores.is_ignored(&entry, is_dir) {
+                    continue;
+                }
+                if is_dir {
+                    if descend && path_matcher.should_file_be_included(&root, &entry) {
+                        folders.push_back((entry, depth + 1, ignores.clone()));
                     }
+                } else if path_matcher.should_file_be_included(&root, &entry) {
+                    files.push_back(entry);
                 }
             }
-            found_files
-        })
-        .collect();
-    result.sort_by(|path1, path2| {
-        let component_num1 = path1.components().count();
-        let component_num2 = path2.components().count();
-        if component_num1 == component_num2 {
-            path1.cmp(path2)
-        } else {
-            component_num1.cmp(&component_num2)
         }
-    });
-    result
+    })
+}
+
+/// Replaces archive with paths of its members, archive which cannot be read is kept
+/// as is and counted as binary file
+fn expand_archive(path: PathBuf) -> Vec<PathBuf> {
+    if ArchiveKind::of(&path).is_none() || !path.is_file() {
+        return vec![path];
+    }
+    member_paths(&path, "").unwrap_or_else(|_| vec![path])
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_decode_sample() {
+        let mut bytes = vec![0xFF, 0xFE];
+        for c in "héllo".encode_utf16() {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        assert_eq!(
+            decode_sample(&bytes, detect_encoding(&bytes)).unwrap(),
+            "héllo"
+        );
+        let latin1 = b"caf\xe9 au lait, tr\xe8s bien";
+        assert_eq!(
+            decode_sample(latin1, detect_encoding(latin1)).unwrap(),
+            "café au lait, très bien"
+        );
+        let binary = [0x00, 0x01, 0x02, 0xff, 0x10];
+        assert!(decode_sample(&binary, detect_encoding(&binary)).is_none());
+    }
+
+    #[test]
+    fn test_non_text_ratio() {
+        assert_eq!(non_text_ratio(b""), 0.0);
+        assert_eq!(non_text_ratio("héllo".as_bytes()), 0.0);
+        // Character cut off by sampling is not counted
+        assert_eq!(non_text_ratio(&"hé".as_bytes()[..2]), 0.0);
+        assert_eq!(non_text_ratio(b"hello worl\xff"), 1.0 / 11.0);
+        assert_eq!(non_text_ratio(b"\x01\x02ab"), 0.5);
+    }
+
+    #[test]
+    fn test_is_text_file_binary_threshold() {
+        let path =
+            std::env::temp_dir().join(format!("tokencount-threshold-{}", std::process::id()));
+        std::fs::write(&path, b"hello world, stray \xff byte").unwrap();
+        let lenient = FileContent::new(path.clone());
+        let strict = FileContent::new(path.clone()).with_binary_threshold(0.0);
+        let results = (lenient.is_text_file(), strict.is_text_file());
+        let content = lenient.read_content().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(results, (true, false));
+        assert_eq!(content, "hello world, stray \u{fffd} byte");
+    }
+
+    #[test]
+    fn test_gzip_content() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("tokencount-gzip-{}.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"hello world\n").unwrap();
+        encoder.finish().unwrap();
+        let file = FileContent::new(path.clone());
+        let is_text = file.is_text_file();
+        let content = file.read_content().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_text);
+        assert_eq!(content, "hello world\n");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("500KB"), Ok(500 * 1024));
+        assert_eq!(parse_size("2mb"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1 GiB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10XB").is_err());
+    }
+
+    #[test]
+    fn test_read_byte_range() {
+        assert_eq!(
+            parse_byte_range("0:4KB"),
+            Ok(ByteRange {
+                start: 0,
+                end: Some(4096)
+            })
+        );
+        assert_eq!(
+            parse_byte_range("10:"),
+            Ok(ByteRange {
+                start: 10,
+                end: None
+            })
+        );
+        assert!(parse_byte_range("10").is_err());
+        assert!(parse_byte_range("10:5").is_err());
+        let file = std::env::temp_dir().join(format!("tokencount-range-{}", std::process::id()));
+        std::fs::write(&file, "aé€b").unwrap();
+        let range = |start, end| read_byte_range(&file, ByteRange { start, end }).unwrap();
+        assert_eq!(range(0, Some(1)), "a");
+        // Both bounds split characters
+        assert_eq!(range(2, Some(5)), "");
+        assert_eq!(range(1, Some(5)), "é");
+        assert_eq!(range(2, None), "€b");
+        assert_eq!(range(0, Some(100)), "aé€b");
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_files() {
+        let dir = std::env::temp_dir().join(format!("tokencount-dedup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt", "d.txt"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        std::fs::write(&files[0], "same").unwrap();
+        std::fs::write(&files[1], "diff").unwrap();
+        std::fs::write(&files[2], "same").unwrap();
+        std::fs::write(&files[3], "other size").unwrap();
+        let (kept, duplicates) = dedup_files(files.clone());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            kept,
+            vec![files[0].clone(), files[1].clone(), files[3].clone()]
+        );
+        assert_eq!(duplicates, 1);
+    }
+
+    #[test]
+    fn test_expand_braces() {
+        assert_eq!(expand_braces("*.{js,ts}"), vec!["*.js", "*.ts"]);
+        assert_eq!(
+            expand_braces("{src,tests}/**/*.{rs,t{xt,oml}}"),
+            vec![
+                "src/**/*.rs",
+                "src/**/*.txt",
+                "src/**/*.toml",
+                "tests/**/*.rs",
+                "tests/**/*.txt",
+                "tests/**/*.toml"
+            ]
+        );
+        assert_eq!(expand_braces("a{,b}"), vec!["a", "ab"]);
+        assert_eq!(expand_braces("a{b,b}"), vec!["ab"]);
+        assert_eq!(expand_braces("{}.txt"), vec!["{}.txt"]);
+        assert_eq!(expand_braces("{a}.txt"), vec!["{a}.txt"]);
+        assert_eq!(expand_braces("{a,b"), vec!["{a,b"]);
+        assert_eq!(expand_braces("[{]a,b}"), vec!["[{]a,b}"]);
+        assert_eq!(expand_braces("{x{a,b}}"), vec!["{xa}", "{xb}"]);
+    }
+
+    #[test]
+    fn test_parse_path_list() {
+        assert_eq!(
+            parse_path_list("a.txt\r\nb c.txt\n\n", false),
+            vec!["a.txt".to_string(), "b c.txt".to_string()]
+        );
+        assert_eq!(
+            parse_path_list("a\nb.txt\0c.txt\0", true),
+            vec!["a\nb.txt".to_string(), "c.txt".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_content_windows_split_on_whitespace() {
+        let data = "hello world foo bar";
+        let windows: Vec<String> = ContentWindows::new(data.as_bytes(), 8)
+            .map(|window| window.unwrap())
+            .collect();
+        assert_eq!(windows.concat(), data);
+        assert_eq!(windows[0], "hello ");
+        assert!(windows.iter().all(|window| !window.is_empty()));
+    }
+
+    #[test]
+    fn test_content_windows_normalize_newlines() {
+        let data = "ab\r\ncd\r\nef";
+        let windows: Vec<String> = ContentWindows::new(data.as_bytes(), 3)
+            .with_newline_normalization(true)
+            .map(|window| window.unwrap())
+            .collect();
+        assert_eq!(windows.concat(), "ab\ncd\nef");
+    }
+
+    #[test]
+    fn test_content_windows_multibyte_boundary() {
+        let data = "ééééé";
+        let windows: Vec<String> = ContentWindows::new(data.as_bytes(), 3)
+            .map(|window| window.unwrap())
+            .collect();
+        assert_eq!(windows.concat(), data);
+        assert_eq!(windows[0], "é");
+    }
+
     #[test]
     fn test_get_matched_files() {
         let top_folder = std::env::current_dir().unwrap();
         let test_data = top_folder.join("resources").join("test_data");
         let files = vec![format!("{}", test_data.to_str().unwrap().to_owned())];
         let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new());
-        let matched_files = get_matched_files(files, config);
+        let matched_files = get_matched_files(files, config).unwrap();
         assert!(!matched_files.is_empty());
         let expected_files = vec![
             test_data.join("a.txt"),
@@ -214,13 +1517,115 @@ mod tests {
         assert_eq!(matched_files, expected_files);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_get_matched_files_follow_symlinks() {
+        let test_data =
+            std::env::temp_dir().join(format!("tokencount-symlinks-{}", std::process::id()));
+        let target = std::env::temp_dir().join(format!(
+            "tokencount-symlink-target-{}.txt",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(test_data.join("sub")).unwrap();
+        std::fs::write(&target, "target").unwrap();
+        std::fs::write(test_data.join("a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink(&target, test_data.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&target, test_data.join("sub").join("link.txt")).unwrap();
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let matched = |recursive, include_symlinks| {
+            let config = FileMatchConfig::new(
+                recursive,
+                include_symlinks,
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+            )
+            .with_order(FileOrder::Name);
+            get_matched_files(files.clone(), config).unwrap()
+        };
+        let top_level = matched(false, false);
+        let top_level_followed = matched(false, true);
+        let recursive = matched(true, false);
+        let recursive_followed = matched(true, true);
+        std::fs::remove_dir_all(&test_data).unwrap();
+        std::fs::remove_file(&target).unwrap();
+        assert_eq!(top_level, vec![test_data.join("a.txt")]);
+        assert_eq!(
+            top_level_followed,
+            vec![test_data.join("a.txt"), test_data.join("link.txt")]
+        );
+        assert_eq!(recursive, vec![test_data.join("a.txt")]);
+        assert_eq!(
+            recursive_followed,
+            vec![
+                test_data.join("a.txt"),
+                test_data.join("link.txt"),
+                test_data.join("sub").join("link.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_matched_files_extensions() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let matched = |extensions: &[&str], ignore_case| {
+            let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+                .with_ignore_case(ignore_case)
+                .with_order(FileOrder::Name)
+                .with_extensions(extensions.iter().map(|ext| ext.to_string()).collect());
+            get_matched_files(files.clone(), config).unwrap()
+        };
+        assert_eq!(matched(&["json"], false), vec![test_data.join("b.json")]);
+        assert_eq!(
+            matched(&[".json", "md"], false),
+            vec![test_data.join("b.json")]
+        );
+        assert!(matched(&["JSON"], false).is_empty());
+        assert_eq!(matched(&["JSON"], true), vec![test_data.join("b.json")]);
+        assert_eq!(matched(&["txt"], false).len(), 5);
+        assert_eq!(matched(&[], false).len(), 6);
+    }
+
+    #[test]
+    fn test_get_matched_files_order() {
+        let test_data =
+            std::env::temp_dir().join(format!("tokencount-order-{}", std::process::id()));
+        std::fs::create_dir_all(test_data.join("a")).unwrap();
+        for (file, content) in [("b.txt", "bbb"), ("a/c.txt", "c"), ("d.txt", "dd")] {
+            std::fs::write(test_data.join(file), content).unwrap();
+        }
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let matched = |order| {
+            let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+                .with_order(order);
+            let matched_files = get_matched_files(files.clone(), config).unwrap();
+            matched_files
+                .iter()
+                .map(|file| file.strip_prefix(&test_data).unwrap().to_path_buf())
+                .collect::<Vec<PathBuf>>()
+        };
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+        let by_depth = matched(FileOrder::Depth);
+        let by_name = matched(FileOrder::Name);
+        let by_size = matched(FileOrder::Size);
+        let mut unsorted = matched(FileOrder::None);
+        std::fs::remove_dir_all(&test_data).unwrap();
+        assert_eq!(by_depth, paths(&["b.txt", "d.txt", "a/c.txt"]));
+        assert_eq!(by_name, paths(&["a/c.txt", "b.txt", "d.txt"]));
+        assert_eq!(by_size, paths(&["a/c.txt", "d.txt", "b.txt"]));
+        unsorted.sort();
+        assert_eq!(unsorted, by_name);
+    }
+
     #[test]
     fn test_get_matched_files_symlinks() {
         let top_folder = std::env::current_dir().unwrap();
         let test_data = top_folder.join("resources").join("test_data");
         let files = vec![format!("{}", test_data.to_str().unwrap().to_owned())];
         let config = FileMatchConfig::new(true, true, Vec::new(), Vec::new(), Vec::new());
-        let matched_files = get_matched_files(files, config);
+        let matched_files = get_matched_files(files, config).unwrap();
         assert!(!matched_files.is_empty());
         let expected_files = vec![
             test_data.join("a.txt"),
@@ -247,7 +1652,7 @@ mod tests {
             Vec::new(),
             Vec::new(),
         );
-        let matched_files = get_matched_files(files, config);
+        let matched_files = get_matched_files(files, config).unwrap();
         assert!(!matched_files.is_empty());
         let expected_files = vec![
             test_data.join("a.txt"),
@@ -259,6 +1664,26 @@ mod tests {
         assert_eq!(matched_files, expected_files);
     }
 
+    #[test]
+    fn test_get_matched_files_include_and_exclude() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            vec!["*.txt".to_owned()],
+            vec!["a*.txt".to_owned()],
+            Vec::new(),
+        );
+        let matched_files = get_matched_files(files, config).unwrap();
+        let expected_files = vec![
+            test_data.join("b_folder").join("ba.txt"),
+            test_data.join("b_folder").join("bb.txt"),
+        ];
+        assert_eq!(matched_files, expected_files);
+    }
+
     #[test]
     fn test_get_matched_files_exlude() {
         let top_folder = std::env::current_dir().unwrap();
@@ -271,7 +1696,7 @@ mod tests {
             vec!["*.txt".to_string()],
             Vec::new(),
         );
-        let matched_files = get_matched_files(files, config);
+        let matched_files = get_matched_files(files, config).unwrap();
         assert!(!matched_files.is_empty());
         let expected_files = vec![test_data.join("b.json")];
         assert_eq!(matched_files, expected_files);
@@ -284,7 +1709,7 @@ mod tests {
         let files = vec![format!("{}", test_data.to_str().unwrap().to_owned())];
         let config =
             FileMatchConfig::new(true, false, Vec::new(), Vec::new(), vec!["a_*".to_string()]);
-        let matched_files = get_matched_files(files, config);
+        let matched_files = get_matched_files(files, config).unwrap();
         assert!(!matched_files.is_empty());
         let expected_files = vec![
             test_data.join("a.txt"),
@@ -294,4 +1719,354 @@ mod tests {
         ];
         assert_eq!(matched_files, expected_files);
     }
+
+    #[test]
+    fn test_get_matched_files_ignore_case() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![format!("{}", test_data.to_str().unwrap().to_owned())];
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            vec!["*.TXT".to_string()],
+            vec!["B*".to_string()],
+            vec!["A_*".to_string()],
+        );
+        let matched_files = get_matched_files(files.clone(), config).unwrap();
+        assert!(matched_files.is_empty());
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            vec!["*.TXT".to_string()],
+            vec!["B*".to_string()],
+            vec!["A_*".to_string()],
+        )
+        .with_ignore_case(true);
+        let matched_files = get_matched_files(files, config).unwrap();
+        assert_eq!(matched_files, vec![test_data.join("a.txt")]);
+    }
+
+    #[test]
+    fn test_get_matched_files_archive() {
+        let test_data = std::env::temp_dir().join(format!("tokencount-zip-{}", std::process::id()));
+        std::fs::create_dir_all(&test_data).unwrap();
+        let archive = test_data.join("data.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for name in ["a.txt", "docs/b.txt", "docs/c.txt"] {
+            zip.start_file(name, options).unwrap();
+        }
+        zip.finish().unwrap();
+        let config = || FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new());
+        let walked = get_matched_files(vec![test_data.to_str().unwrap().to_owned()], config());
+        let member = format!("{}:docs", archive.to_str().unwrap());
+        let selected = get_matched_files(vec![member], config());
+        let missing = format!("{}:d.txt", archive.to_str().unwrap());
+        let missing = get_matched_files(vec![missing], config());
+        let content = FileContent::new(test_data.join("data.zip:a.txt")).read_content();
+        std::fs::remove_dir_all(&test_data).unwrap();
+        assert_eq!(
+            walked.unwrap(),
+            vec![
+                test_data.join("data.zip:a.txt"),
+                test_data.join("data.zip:docs/b.txt"),
+                test_data.join("data.zip:docs/c.txt"),
+            ]
+        );
+        assert_eq!(
+            selected.unwrap(),
+            vec![
+                test_data.join("data.zip:docs/b.txt"),
+                test_data.join("data.zip:docs/c.txt"),
+            ]
+        );
+        assert!(matches!(missing, Err(FileMatchError::PathNotFound(_))));
+        assert_eq!(content.unwrap(), "");
+    }
+
+    #[test]
+    fn test_get_matched_files_invert() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            vec!["a*.txt".to_string()],
+            Vec::new(),
+            vec!["b_*".to_string()],
+        )
+        .with_invert(true);
+        let matched_files = get_matched_files(files.clone(), config).unwrap();
+        assert_eq!(matched_files, vec![test_data.join("b.json")]);
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            Vec::new(),
+            vec!["*.txt".to_string()],
+            vec!["a_*".to_string()],
+        )
+        .with_invert(true);
+        let matched_files = get_matched_files(files, config).unwrap();
+        let expected_files = vec![
+            test_data.join("a.txt"),
+            test_data.join("b_folder").join("ba.txt"),
+            test_data.join("b_folder").join("bb.txt"),
+        ];
+        assert_eq!(matched_files, expected_files);
+    }
+
+    #[test]
+    fn test_matched_files_iter() {
+        let test_data = std::env::current_dir()
+            .unwrap()
+            .join("resources")
+            .join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned(), "missing".to_owned()];
+        for gitignore in [false, true] {
+            let config = || {
+                FileMatchConfig::new(true, false, Vec::new(), Vec::new(), vec!["b_*".to_owned()])
+                    .with_gitignore(gitignore)
+            };
+            let mut found: Vec<Result<PathBuf, FileMatchError>> =
+                matched_files_iter(files.clone(), config())
+                    .unwrap()
+                    .collect();
+            assert!(matches!(
+                found.pop(),
+                Some(Err(FileMatchError::PathNotFound(_)))
+            ));
+            let mut found: Vec<PathBuf> = found.into_iter().map(Result::unwrap).collect();
+            found.sort();
+            let mut expected = get_matched_files(files[..1].to_vec(), config()).unwrap();
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_get_matched_files_missing_path() {
+        let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new());
+        let result = get_matched_files(vec!["not_existing_path".to_string()], config);
+        assert!(matches!(result, Err(FileMatchError::PathNotFound(_))));
+    }
+
+    #[test]
+    fn test_get_matched_files_not_recursive_dir() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(false, false, Vec::new(), Vec::new(), Vec::new())
+            .with_order(FileOrder::Name);
+        let result = get_matched_files(files.clone(), config).unwrap();
+        assert_eq!(
+            result,
+            vec![test_data.join("a.txt"), test_data.join("b.json")]
+        );
+        let config = FileMatchConfig::new(
+            false,
+            false,
+            vec!["*.txt".to_string()],
+            Vec::new(),
+            Vec::new(),
+        );
+        let result: Vec<PathBuf> = matched_files_iter(files, config)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(result, vec![test_data.join("a.txt")]);
+    }
+
+    #[test]
+    fn test_get_matched_files_invalid_pattern() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config =
+            FileMatchConfig::new(true, false, vec!["[".to_string()], Vec::new(), Vec::new());
+        let result = get_matched_files(files, config);
+        assert!(matches!(result, Err(FileMatchError::InvalidPattern { .. })));
+    }
+
+    #[test]
+    fn test_get_matched_files_gitignore() {
+        let test_data =
+            std::env::temp_dir().join(format!("tokencount-gitignore-{}", std::process::id()));
+        std::fs::create_dir_all(test_data.join("target")).unwrap();
+        std::fs::write(test_data.join(".gitignore"), "target/\n*.log\n").unwrap();
+        std::fs::write(test_data.join("a.txt"), "a").unwrap();
+        std::fs::write(test_data.join("b.log"), "b").unwrap();
+        std::fs::write(test_data.join("target").join("c.txt"), "c").unwrap();
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+            .with_gitignore(true);
+        let matched_files = get_matched_files(files, config).unwrap();
+        std::fs::remove_dir_all(&test_data).unwrap();
+        let expected_files = vec![test_data.join("a.txt")];
+        assert_eq!(matched_files, expected_files);
+    }
+
+    #[test]
+    fn test_get_matched_files_hidden() {
+        let test_data =
+            std::env::temp_dir().join(format!("tokencount-hidden-{}", std::process::id()));
+        std::fs::create_dir_all(test_data.join(".git")).unwrap();
+        std::fs::write(test_data.join(".git").join("config"), "a").unwrap();
+        std::fs::write(test_data.join(".env"), "b").unwrap();
+        std::fs::write(test_data.join("a.txt"), "c").unwrap();
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let mut matched = Vec::new();
+        for (gitignore, hidden) in [(false, false), (true, false), (false, true), (true, true)] {
+            let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+                .with_gitignore(gitignore)
+                .with_hidden(hidden);
+            matched.push(get_matched_files(files.clone(), config).unwrap());
+        }
+        let explicit = vec![test_data.join(".env").to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(false, false, Vec::new(), Vec::new(), Vec::new());
+        let explicit_matched = get_matched_files(explicit, config).unwrap();
+        std::fs::remove_dir_all(&test_data).unwrap();
+        let visible = vec![test_data.join("a.txt")];
+        let all = vec![
+            test_data.join(".env"),
+            test_data.join("a.txt"),
+            test_data.join(".git").join("config"),
+        ];
+        assert_eq!(matched, vec![visible.clone(), visible, all.clone(), all]);
+        assert_eq!(explicit_matched, vec![test_data.join(".env")]);
+    }
+
+    #[test]
+    fn test_get_matched_files_max_depth() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        for gitignore in [false, true] {
+            let config = FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+                .with_gitignore(gitignore)
+                .with_max_depth(Some(0));
+            let matched_files = get_matched_files(files.clone(), config).unwrap();
+            let expected_files = vec![test_data.join("a.txt"), test_data.join("b.json")];
+            assert_eq!(matched_files, expected_files);
+        }
+    }
+
+    #[test]
+    fn test_get_matched_files_include_relative_path() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            vec!["a_folder/*.txt".to_string(), "**/bb.txt".to_string()],
+            Vec::new(),
+            Vec::new(),
+        );
+        let matched_files = get_matched_files(files, config).unwrap();
+        let expected_files = vec![
+            test_data.join("a_folder").join("aa.txt"),
+            test_data.join("a_folder").join("ab.txt"),
+            test_data.join("b_folder").join("bb.txt"),
+        ];
+        assert_eq!(matched_files, expected_files);
+    }
+
+    #[test]
+    fn test_get_matched_files_exclude_relative_path() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let config = FileMatchConfig::new(
+            true,
+            false,
+            Vec::new(),
+            vec!["b_folder/*".to_string()],
+            Vec::new(),
+        );
+        let matched_files = get_matched_files(files, config).unwrap();
+        let expected_files = vec![
+            test_data.join("a.txt"),
+            test_data.join("b.json"),
+            test_data.join("a_folder").join("aa.txt"),
+            test_data.join("a_folder").join("ab.txt"),
+        ];
+        assert_eq!(matched_files, expected_files);
+    }
+
+    #[test]
+    fn test_relative_path_to_common_ancestor() {
+        let top_folder = std::env::current_dir().unwrap();
+        let test_data = top_folder.join("resources").join("test_data");
+        let files = vec![test_data.join("a.txt"), top_folder.join("Cargo.toml")];
+        let root = common_ancestor(&files).unwrap();
+        let relative: Vec<PathBuf> = files
+            .iter()
+            .map(|file| relative_path(file, &root))
+            .collect();
+        assert_eq!(root, top_folder);
+        assert_eq!(
+            relative,
+            vec![
+                PathBuf::from("resources/test_data/a.txt"),
+                PathBuf::from("Cargo.toml")
+            ]
+        );
+        assert_eq!(
+            relative_path(&top_folder, &test_data),
+            PathBuf::from("../..")
+        );
+        assert_eq!(relative_path(&test_data, &test_data), PathBuf::from("."));
+        assert_eq!(
+            common_ancestor(std::slice::from_ref(&test_data)),
+            Some(test_data)
+        );
+    }
+
+    #[test]
+    fn test_get_matched_files_ignore_file() {
+        let test_data =
+            std::env::temp_dir().join(format!("tokencount-ignore-file-{}", std::process::id()));
+        std::fs::create_dir_all(test_data.join("gen")).unwrap();
+        std::fs::create_dir_all(test_data.join("sub")).unwrap();
+        std::fs::write(test_data.join(IGNORE_FILE), "gen/\n*.log\n!keep.log\n").unwrap();
+        std::fs::write(test_data.join("sub").join(IGNORE_FILE), "/c.txt\n").unwrap();
+        for file in [
+            "a.txt",
+            "x.log",
+            "keep.log",
+            "gen/b.txt",
+            "sub/c.txt",
+            "sub/d.txt",
+        ] {
+            std::fs::write(test_data.join(file), "a").unwrap();
+        }
+        let files = vec![test_data.to_str().unwrap().to_owned()];
+        let mut matched = Vec::new();
+        for (gitignore, ignore_file) in [(false, true), (true, true), (false, false)] {
+            let config = || {
+                FileMatchConfig::new(true, false, Vec::new(), Vec::new(), Vec::new())
+                    .with_gitignore(gitignore)
+                    .with_ignore_file(ignore_file)
+            };
+            let mut lazy: Vec<PathBuf> = matched_files_iter(files.clone(), config())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            lazy.sort();
+            let mut walked = get_matched_files(files.clone(), config()).unwrap();
+            walked.sort();
+            assert_eq!(lazy, walked);
+            matched.push(walked);
+        }
+        std::fs::remove_dir_all(&test_data).unwrap();
+        let kept = vec![
+            test_data.join("a.txt"),
+            test_data.join("keep.log"),
+            test_data.join("sub").join("d.txt"),
+        ];
+        assert_eq!(matched[0], kept);
+        assert_eq!(matched[1], kept);
+        assert_eq!(matched[2].len(), 6);
+    }
 }
@@ -0,0 +1,17 @@
+pub mod archive;
+pub mod backend;
+pub mod chat_template;
+pub mod config;
+pub mod count_cache;
+pub mod counter;
+pub mod files;
+pub mod lockfile;
+pub mod output;
+pub mod tokenizer_cache;
+pub mod variables;
+
+pub use backend::CountTokens;
+pub use counter::TokenCounter;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
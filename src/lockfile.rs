@@ -0,0 +1,133 @@
+use crate::Result;
+use serde_json::{Value, json};
+use std::fmt;
+use std::path::Path;
+
+/// Lockfile with pinned tokenizers looked up in the current directory
+pub const LOCK_FILE: &str = "tokencount.lock";
+
+/// Tokenizer resolved for a run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockedTokenizer {
+    /// Name printed in output, e.g. model identifier, json config path or encoding
+    pub identifier: String,
+    /// Hub revision, `None` for tokenizers not downloaded from the hub
+    pub revision: Option<String>,
+    /// Fingerprint of tokenizer definition, `None` for backends without definition
+    pub hash: Option<String>,
+}
+
+impl fmt::Display for LockedTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.identifier)?;
+        if let Some(revision) = &self.revision {
+            write!(f, "@{revision}")?;
+        }
+        if let Some(hash) = &self.hash {
+            write!(f, " ({hash})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Tokenizers pinned for reproducible counts, stored as JSON
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    pub tokenizers: Vec<LockedTokenizer>,
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read lockfile {}: {e}", path.display()))?;
+        let invalid = || format!("Invalid lockfile {}", path.display());
+        let value: Value = serde_json::from_str(&content).map_err(|_| invalid())?;
+        let tokenizers = value["tokenizers"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|tokenizer| {
+                let optional = |key: &str| tokenizer[key].as_str().map(str::to_string);
+                Some(LockedTokenizer {
+                    identifier: optional("identifier")?,
+                    revision: optional("revision"),
+                    hash: optional("hash"),
+                })
+            })
+            .collect::<Option<Vec<LockedTokenizer>>>()
+            .ok_or_else(invalid)?;
+        Ok(Lockfile { tokenizers })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tokenizers: Vec<Value> = self
+            .tokenizers
+            .iter()
+            .map(|tokenizer| {
+                json!({
+                    "identifier": tokenizer.identifier,
+                    "revision": tokenizer.revision,
+                    "hash": tokenizer.hash,
+                })
+            })
+            .collect();
+        let content = serde_json::to_string_pretty(&json!({ "tokenizers": tokenizers }))?;
+        std::fs::write(path, content + "\n")?;
+        Ok(())
+    }
+
+    /// Checks that `resolved` tokenizers are the same as locked ones
+    pub fn verify(&self, resolved: &Lockfile) -> Result<()> {
+        if self.tokenizers.len() != resolved.tokenizers.len() {
+            return Err(format!(
+                "Lockfile pins {} tokenizers, but {} are used",
+                self.tokenizers.len(),
+                resolved.tokenizers.len()
+            )
+            .into());
+        }
+        for (locked, used) in self.tokenizers.iter().zip(&resolved.tokenizers) {
+            if locked != used {
+                return Err(format!("Tokenizer {used} differs from locked {locked}").into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load_and_verify() {
+        let path = std::env::temp_dir().join(format!("tokencount-{}.lock", std::process::id()));
+        let lockfile = Lockfile {
+            tokenizers: vec![
+                LockedTokenizer {
+                    identifier: "gpt2".to_string(),
+                    revision: Some("main".to_string()),
+                    hash: Some("abc".to_string()),
+                },
+                LockedTokenizer {
+                    identifier: "cl100k_base".to_string(),
+                    revision: None,
+                    hash: None,
+                },
+            ],
+        };
+        lockfile.save(&path).unwrap();
+        let loaded = Lockfile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, lockfile);
+        assert!(loaded.verify(&lockfile).is_ok());
+
+        let mut changed = lockfile.clone();
+        changed.tokenizers[0].hash = Some("def".to_string());
+        assert_eq!(
+            loaded.verify(&changed).unwrap_err().to_string(),
+            "Tokenizer gpt2@main (def) differs from locked gpt2@main (abc)"
+        );
+        assert!(loaded.verify(&Lockfile::default()).is_err());
+    }
+}
@@ -1,56 +1,530 @@
-pub mod files;
-
-use clap::Parser;
-use files::{FileContent, FileMatchConfig, get_matched_files};
+use bzip2::read::MultiBzDecoder;
+use clap::{ArgGroup, Parser, ValueEnum};
+use flate2::read::MultiGzDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
+use notify::{RecursiveMode, Watcher};
+use rayon::prelude::*;
+use regex::Regex;
 use std::collections::HashMap;
 use std::env;
-use std::io::{IsTerminal, Read};
-use tokenizers::{FromPretrainedParameters, Tokenizer};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+use tokencount::archive::ArchiveMember;
+use tokencount::backend::{
+    CommandCounter, ConfiguredTokenizer, Encoding, TextUnit, TiktokenCounter,
+};
+use tokencount::chat_template::{ChatTemplate, TOKENIZER_CONFIG_FILE};
+use tokencount::config::{CONFIG_FILE, Config};
+use tokencount::count_cache::CountCache;
+use tokencount::counter::{BINARY_FILE_REASON, DEFAULT_STREAM_THRESHOLD, FileCount};
+use tokencount::files::{
+    ByteRange, DEFAULT_BINARY_THRESHOLD, DEFAULT_SAMPLE_SIZE, FileMatchConfig, FileOrder,
+    common_ancestor, dedup_files, get_matched_files, git_changed_files, normalize_newlines,
+    parse_byte_range, parse_path_list, parse_size, read_byte_range, relative_path,
+};
+use tokencount::lockfile::{LOCK_FILE, LockedTokenizer, Lockfile};
+use tokencount::output::{
+    Benchmark, GroupBy, OutputFormat, OutputWriter, SortOrder, Template, TokenizerInfo, benchmark,
+    compare_counts, comparison, histogram, offsets, tokenizer_info, tree,
+};
+use tokencount::tokenizer_cache::{TokenizerCache, model_revisions};
+use tokencount::variables::Variables;
+use tokencount::{CountTokens, Result, TokenCounter};
+#[cfg(feature = "hub")]
+use tokenizers::FromPretrainedParameters;
+use tokenizers::Tokenizer;
 
 const DEFAULT_TOKENIZER: &str = "bert-base-uncased";
 const TOKEN_COUNT_MODEL_VAR: &str = "TOKEN_COUNT_MODEL";
 const TOKEN_COUNT_FILE_VAR: &str = "TOKEN_COUNT_JSON_CONFIG";
-const FILE_CHUNK_SIZE: usize = 20;
+/// Tokenizer file name inside model directory
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const HF_HUB_OFFLINE_VAR: &str = "HF_HUB_OFFLINE";
+/// Disables automatic colors when set to a non empty value, see https://no-color.org
+const NO_COLOR_VAR: &str = "NO_COLOR";
+/// Environment variables with Huggingface token in order of precedence
+#[cfg(feature = "hub")]
+const HF_TOKEN_VARS: [&str; 2] = ["HF_TOKEN", "HUGGING_FACE_HUB_TOKEN"];
+const PROGRESS_MIN_FILES: usize = 1000;
+/// Delay before the first retry of failed download, doubled for each next retry
+#[cfg(feature = "hub")]
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Suffix of column name with counts of tokenizer with loaded padding and truncation
+const CONFIGURED_SUFFIX: &str = "configured";
+/// Path printed for the count of files joined with `--concat`
+const CONCAT_LABEL: &str = "(concatenated)";
+/// Maximum number of characters of text printed as label of `--text` count
+const TEXT_LABEL_LENGTH: usize = 40;
+/// Value of `--chat-template` to look up template in tokenizer config
+const CHAT_TEMPLATE_AUTO: &str = "auto";
+/// Number of stdin lines encoded in one batch with `--stdin-lines`
+const STDIN_LINES_BATCH: usize = 1000;
+/// Quiet period after the last file change before recounting in watch mode
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+/// Exit code used when no files matched paths and patterns
+const NO_MATCHES_EXIT_CODE: i32 = 2;
+/// Exit code used when some files could not be read, printed counts are partial
+const READ_ERROR_EXIT_CODE: i32 = 3;
+/// Exit code used with `--fail-on-binary` when binary files were matched
+const BINARY_FILE_EXIT_CODE: i32 = 4;
+/// Exit code used when counting was interrupted by Ctrl-C, same as shells use for SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+/// Paragraph of prose, code, numbers and non-ASCII text of synthetic `--benchmark` corpus
+const BENCHMARK_TEXT: &str = "The quick brown fox jumps over the lazy dog near the riverbank. \
+    fn main() { let total: u64 = (1..=100).map(|n| n * n).sum(); println!(\"{total}\"); } \
+    Prices rose 3.75% to $1,024.50 on 2024-03-15. Grüße aus München, café naïve, 東京 and \
+    Санкт-Петербург. <div class=\"note\">Tokenizers split rare words like antidisestablishment.</div>\n";
+/// Number of documents of synthetic `--benchmark` corpus, document N repeats the text
+/// 1 + N % 8 times
+const BENCHMARK_DOCUMENTS: usize = 256;
 
-type Error = Box<dyn std::error::Error + Send + Sync>;
-type Result<T> = std::result::Result<T, Error>;
+/// Minimum level of diagnostic messages printed to stderr
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// When to colorize text output
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ColorChoice {
+    /// Colorize if output is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Unit of measure printed for each file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum CountMode {
+    #[default]
+    Tokens,
+    Lines,
+    Words,
+    Bytes,
+}
+
+impl CountMode {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    fn text_unit(self) -> Option<TextUnit> {
+        match self {
+            CountMode::Tokens => None,
+            CountMode::Lines => Some(TextUnit::Lines),
+            CountMode::Words => Some(TextUnit::Words),
+            CountMode::Bytes => Some(TextUnit::Bytes),
+        }
+    }
+}
 
 /// Token count utility
 /// Counts tokens in files using provided tokenizer model.
 /// If no model is provided, it uses `bert-base-uncased` by default.
 #[derive(Parser, Debug)]
+#[command(group(
+    ArgGroup::new("tokenizer").args(["identifier", "json_config", "encoding", "tokenizer_cmd"])
+))]
 struct Arguments {
-    /// Name of tokenizer model to use, only one of identifier or file can be used
+    /// Path of TOML config with default recursive, model, include, exclude and exclude-dir
+    /// values. `.tokencount.toml` in current directory is used if it exists
+    #[arg(long, default_value = None)]
+    config: Option<PathBuf>,
+    /// Name of tokenizer model to use, only one of identifier, json config, encoding or
+    /// tokenizer command can be used.
+    /// Can be passed multiple times to compare counts of several tokenizers.
+    /// Path of local model directory with tokenizer.json is loaded without download
     #[arg(short, long, default_value = None)]
-    identifier: Option<String>,
-    /// Path to json config, only one of identifier or file can be used
+    identifier: Vec<String>,
+    /// Path to json config, `.gz` and `.bz2` configs are decompressed. Only one of identifier,
+    /// json config, encoding or tokenizer command can be used
     #[arg(short, long, default_value = None)]
     json_config: Option<String>,
-    /// Revision of model tokenizer
+    /// Render each text as a user message of chat template before counting. `auto` reads
+    /// tokenizer_config.json next to local tokenizer and counts without template if there is
+    /// none, otherwise path of tokenizer_config.json or Jinja template file
+    #[arg(long, default_value = None)]
+    chat_template: Option<String>,
+    /// Value of `{{key}}` placeholder substituted in texts before counting, e.g.
+    /// `--vars name=Alice`. Can be passed multiple times
+    #[arg(long, value_name = "KEY=VALUE", value_parser = Variables::parse_assignment)]
+    vars: Vec<(String, String)>,
+    /// OpenAI tiktoken encoding to use instead of huggingface tokenizer
+    #[arg(short, long, value_enum, default_value = None)]
+    encoding: Option<Encoding>,
+    /// External command which reads text from stdin and prints token count, e.g.
+    /// `mytok --count`. It is started once per file
+    #[arg(long, default_value = None)]
+    tokenizer_cmd: Option<String>,
+    /// Revision of model tokenizer. Can be passed multiple times to compare revisions: a single
+    /// identifier is loaded at every revision, otherwise revisions are paired with identifiers.
+    /// Only models from the hub can be loaded at several revisions
     #[arg(long, default_value = "main")]
-    revision: String,
-    /// Huggingface token in case download tokenizer requires authentification
+    revision: Vec<String>,
+    /// Huggingface token in case download tokenizer requires authentification. Visible in
+    /// process list, prefer --token-file or HF_TOKEN environment variable
     #[arg(short, long, default_value = None)]
     token: Option<String>,
-    /// Read all files under each directory recursively, exclude symbolic links
+    /// Path of file with Huggingface token, takes precedence over environment and --token
+    #[arg(long, default_value = None)]
+    token_file: Option<String>,
+    /// Do not use local cache of downloaded tokenizers, always download tokenizer
+    #[arg(long, default_value_t = false)]
+    no_cache: bool,
+    /// Number of retries of tokenizer download after network or server errors,
+    /// delay between retries doubles starting from 1 second
+    #[arg(long, default_value_t = 3)]
+    download_retries: u32,
+    /// Write identifiers, revisions and hashes of used tokenizers to tokencount.lock
+    #[arg(long, default_value_t = false, conflicts_with = "locked")]
+    lock: bool,
+    /// Fail if used tokenizers differ from tokenizers pinned in tokencount.lock
+    #[arg(long, default_value_t = false)]
+    locked: bool,
+    /// Never download tokenizers, use only json config, local model directory or cache.
+    /// Also enabled by HF_HUB_OFFLINE=1
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+    /// Read all files under each directory recursively, exclude symbolic links. Without it
+    /// only files directly inside passed directories are read
     #[arg(short, long, default_value_t = false)]
     recursive: bool,
-    /// Read all files under each directory recursively, include symbolic links
+    /// Read all files under each directory recursively, include symbolic links.
+    /// Same as `-r --follow-symlinks`
     #[arg(short = 'R', long, default_value_t = false)]
     dereference_recursive: bool,
+    /// Follow symbolic links found inside directories, with or without `-r`. Paths passed
+    /// as arguments are always followed
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+    /// Skip files ignored by .gitignore files found during recursive walk
+    #[arg(long, default_value_t = false)]
+    gitignore: bool,
+    /// Do not skip files ignored by .tokencountignore files found during recursive walk
+    #[arg(long, default_value_t = false)]
+    no_ignore_file: bool,
+    /// Maximum depth of directories to descend below each walked directory,
+    /// 0 means only files directly inside it
+    #[arg(long, default_value = None)]
+    max_depth: Option<usize>,
+    /// Load tokenizer once and print token count of each line read from stdin until EOF
+    #[arg(long, default_value_t = false)]
+    repl: bool,
+    /// Count tokens of the given text instead of files or stdin, can be passed multiple times
+    #[arg(long, default_value = None, conflicts_with_all = ["files", "files_from", "repl"])]
+    text: Vec<String>,
+    /// In REPL mode count paragraphs terminated by blank line instead of single lines
+    #[arg(long, default_value_t = false, requires = "repl")]
+    multiline: bool,
+    /// Print files selected by paths and patterns without loading tokenizer and counting
+    #[arg(long, default_value_t = false)]
+    list_only: bool,
+    /// Do not fail with exit code 2 when no files matched
+    #[arg(long, default_value_t = false)]
+    allow_empty: bool,
+    /// Order of matched files, `none` keeps order in which files are found without sorting,
+    /// `mtime` and `size` stat each file
+    #[arg(long, value_enum, default_value_t = FileOrder::Depth)]
+    order: FileOrder,
     /// Glob. Show count only for files that names only matched to glob pattern.
-    /// If include and exclude patterns are passed, include wins.
+    /// If include and exclude patterns are passed, files must match include and not exclude.
     #[arg(long, default_value = None)]
     include: Vec<String>,
+    /// Comma separated extensions of files to count, e.g. `rs,md,txt`. Checked before
+    /// patterns, so other files are skipped without opening them
+    #[arg(long, value_delimiter = ',')]
+    ext: Vec<String>,
     /// Glob pattern of files to exclude
     #[arg(long, default_value = None)]
     exclude: Vec<String>,
     /// Glob pattern of directories to exclude from counting
     #[arg(long, default_value = None)]
     exclude_dir: Vec<String>,
+    /// Match include, exclude and exclude-dir patterns case insensitively
+    #[arg(long, default_value_t = false)]
+    ignore_case: bool,
+    /// Count files not selected by include and exclude patterns, exclude-dir patterns still
+    /// skip directories
+    #[arg(long, default_value_t = false)]
+    invert: bool,
+    /// Include files and directories whose name starts with `.` found during recursion
+    #[arg(long, default_value_t = false)]
+    hidden: bool,
+    /// Name of piped content printed instead of file path
+    #[arg(long, default_value = ".")]
+    stdin_name: String,
+    /// Cache token counts in file, unchanged files are not read and encoded again
+    #[arg(long, default_value = None)]
+    cache_file: Option<String>,
     /// Print token count per file
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
+    /// Print word and char counts next to token counts
+    #[arg(long, default_value_t = false)]
+    stats: bool,
+    /// Order of per-file output
+    #[arg(long, value_enum, default_value_t = SortOrder::Path)]
+    sort: SortOrder,
+    /// Print only N files with the largest token count followed by total
+    #[arg(long, default_value = None)]
+    top: Option<usize>,
+    /// Terminate path and count fields of text output with NUL bytes instead of spaces and
+    /// newlines, like `find -print0`
+    #[arg(short = '0', long, default_value_t = false)]
+    print0: bool,
+    /// Colorize text output: dim counts and print paths of files above `--threshold` in red.
+    /// `auto` colorizes terminal output unless NO_COLOR is set
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Separator of path and count fields of text output, `\t` is unescaped
+    #[arg(long, default_value = " ", value_parser = unescape)]
+    separator: String,
+    /// Count all matched files joined with separator as one document instead of each file
+    #[arg(long, default_value_t = false, conflicts_with_all = ["text", "repl", "cache_file"])]
+    concat: bool,
+    /// Separator of files joined with `--concat`, `\n` and `\t` are unescaped
+    #[arg(long, default_value = "\\n", value_parser = unescape)]
+    concat_separator: String,
+    /// After counting, watch matched paths and print updated total whenever files change
+    #[arg(long, default_value_t = false, conflicts_with_all = ["text", "repl", "list_only"])]
+    watch: bool,
+    /// Count only about the first N tokens of each file, counts are capped at N
+    #[arg(long, default_value = None, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    head_tokens: Option<usize>,
+    /// Count only bytes START..END of a single file, e.g. `0:4KB`. Bounds can be omitted and
+    /// characters split by them are not counted
+    #[arg(
+        long,
+        value_name = "START:END",
+        value_parser = parse_byte_range,
+        conflicts_with_all = [
+            "text", "repl", "concat", "per_line", "watch", "cache_file", "count_paths", "offsets"
+        ]
+    )]
+    byte_range: Option<ByteRange>,
+    /// Count tokens of each line of files separately and print `path:lineno count`. With
+    /// `--summary` statistics of tokens per line are printed.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["concat", "head_tokens"])]
+    per_line: bool,
+    /// In pipe mode count each line of stdin separately instead of the whole input
+    #[arg(long, default_value_t = false)]
+    stdin_lines: bool,
+    /// Count each distinct file content once, number of skipped duplicates is printed to stderr
+    #[arg(long, default_value_t = false)]
+    dedup: bool,
+    /// Exit with code 4 and list binary files instead of silently skipping them
+    #[arg(long, default_value_t = false)]
+    fail_on_binary: bool,
+    /// Treat files which are not valid UTF-8 like binary files and exit with code 4 listing
+    /// them, instead of skipping them or replacing invalid bytes. Checks `--sample-size` bytes
+    #[arg(long, default_value_t = false, conflicts_with_all = ["encoding_detect", "binary_threshold"])]
+    strict_encoding: bool,
+    /// Print indented tree of directories with recursive token totals instead of the count,
+    /// files are listed too in verbose mode
+    #[arg(long, default_value_t = false)]
+    tree: bool,
+    /// Print paths relative to DIR, without DIR relative to the common ancestor directory of
+    /// counted files
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_value = None)]
+    relative_to: Option<Option<String>>,
+    /// Print histogram of number of files per token count in power of two buckets to stderr
+    #[arg(long, default_value_t = false)]
+    histogram: bool,
+    /// Count tokens of paths of matched files instead of their contents, files are not read
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["text", "repl", "concat", "per_line", "cache_file", "head_tokens", "offsets"]
+    )]
+    count_paths: bool,
+    /// Compare two directory trees, files are matched by path relative to each root and tokens
+    /// of added, removed and changed files are reported. Changed files are listed in verbose mode
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["DIR_A", "DIR_B"],
+        conflicts_with_all = [
+            "files", "text", "repl", "files_from", "git_diff", "concat", "per_line", "watch",
+            "offsets", "tree", "count_paths"
+        ]
+    )]
+    compare: Vec<String>,
+    /// List counted files without tokens and their number to stderr, e.g. to find stub files.
+    /// Binary files are skipped before counting, so only empty text files are listed
+    #[arg(long, default_value_t = false, conflicts_with_all = ["concat", "per_line"])]
+    count_empty: bool,
+    /// Print tokens of a single file, text or stdin with byte offsets of their source text
+    /// instead of counts. Text format prints tab separated values
+    #[arg(long, default_value_t = false, conflicts_with_all = ["repl", "watch", "chat_template"])]
+    offsets: bool,
+    /// Print source, vocabulary size, special tokens and fingerprint of loaded tokenizers and
+    /// exit without counting
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["text", "repl", "watch", "offsets", "compare", "list_only"]
+    )]
+    tokenizer_info: bool,
+    /// Count matched files, `--text` or a built-in synthetic corpus N times and print tokens,
+    /// files and bytes per second instead of counts
+    #[arg(
+        long,
+        value_name = "RUNS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        conflicts_with_all = [
+            "repl", "watch", "offsets", "compare", "tokenizer_info", "list_only", "concat",
+            "per_line", "count_paths", "cache_file", "byte_range", "tree"
+        ]
+    )]
+    benchmark: Option<usize>,
+    /// Print only files with at least N tokens, totals still include all files
+    #[arg(long, default_value = None)]
+    min_tokens: Option<usize>,
+    /// Print number of tokens beyond context window of N tokens per file and in total
+    #[arg(long, default_value = None)]
+    context_window: Option<usize>,
+    /// Print number of chunks of N tokens per file and in total, e.g. for sliding window
+    /// chunking estimation
+    #[arg(long, default_value = None, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    chunk_window: Option<usize>,
+    /// Number of tokens shared by consecutive chunks of `--chunk-window`, less than the window
+    #[arg(long, default_value_t = 0, requires = "chunk_window")]
+    chunk_overlap: usize,
+    /// Print counts as share of budget of N tokens, e.g. `1234/8000 (15.4%)`
+    #[arg(long, default_value = None, value_parser = clap::value_parser!(u64).range(1..))]
+    budget: Option<u64>,
+    /// Template of per-file lines in verbose text output, e.g. `{tokens}\t{path}`.
+    /// Placeholders: {path}, {tokens}, {words}, {chars}, {overflow}, {chunks}
+    #[arg(long, default_value = None, value_parser = Template::parse)]
+    template: Option<Template>,
+    /// Print time spent in file discovery, tokenizer loading, encoding and in total to stderr
+    #[arg(long, default_value_t = false)]
+    timing: bool,
+    /// Print `files=N tokens=T avg=A` summary line to stderr after the count
+    #[arg(long, default_value_t = false)]
+    summary: bool,
+    /// Print token count subtotals per directory followed by total
+    #[arg(long, default_value_t = false)]
+    group_by_dir: bool,
+    /// Print token count subtotals per file extension sorted by descending count
+    #[arg(long, default_value_t = false, conflicts_with = "group_by_dir")]
+    by_extension: bool,
+    /// Print token count subtotals per first capture group of regex matched against path,
+    /// e.g. `_(en|de)\.`. Files which do not match are grouped as `(unmatched)`
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = Regex::new,
+        conflicts_with_all = ["group_by_dir", "by_extension"]
+    )]
+    group_by_regex: Option<Regex>,
+    /// Unit to count. Tokenizer is not loaded for lines, words and bytes
+    #[arg(long, value_enum, default_value_t = CountMode::Tokens)]
+    count_mode: CountMode,
+    /// Write counts to file instead of stdout, file is created or truncated.
+    /// Warnings are still printed to stderr
+    #[arg(short, long, default_value = None)]
+    output: Option<String>,
+    /// Output format. In verbose mode prints count per file, otherwise total count.
+    /// Ndjson prints a line per file as soon as it is counted followed by the total
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Number of threads used to read and encode files. Defaults to number of cpus
+    #[arg(long, default_value = None)]
+    jobs: Option<usize>,
+    /// Number of files read and encoded in one batch by a thread. Larger batches use more
+    /// memory for higher throughput. Picked by available memory and file sizes by default
+    #[arg(long, default_value = None, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    chunk_size: Option<usize>,
+    /// Size of file in bytes starting from which file is read and encoded by windows
+    /// instead of loading whole file into memory
+    #[arg(long, default_value_t = DEFAULT_STREAM_THRESHOLD)]
+    stream_threshold: u64,
+    /// Number of bytes from the beginning of file used to decide if it is text,
+    /// e.g. `8KB`
+    #[arg(long, default_value_t = DEFAULT_SAMPLE_SIZE as u64, value_parser = parse_size)]
+    sample_size: u64,
+    /// File is treated as binary if fraction of invalid or non printable bytes in
+    /// sample exceeds threshold. Files with NUL bytes are always binary
+    #[arg(long, default_value_t = DEFAULT_BINARY_THRESHOLD, value_parser = parse_ratio)]
+    binary_threshold: f64,
+    /// Skip files larger than size, e.g. `500KB`, `10MB` or `1GB`
+    #[arg(long, default_value = None, value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    /// Exit with code 1 if total token count (or count of any file in verbose mode)
+    /// exceeds threshold
+    #[arg(long, default_value = None)]
+    threshold: Option<usize>,
+    /// Read paths of files to count from file, `-` means stdin. Paths are separated by newlines
+    #[arg(long, default_value = None)]
+    files_from: Option<String>,
+    /// Count files changed in git repository of current directory instead of walking paths:
+    /// staged files, or with `--git-diff=REF` files which differ from REF. Paths limit
+    /// changed files to them
+    #[arg(
+        long,
+        value_name = "REF",
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = None
+    )]
+    git_diff: Option<Option<String>>,
+    /// Paths passed with --files-from are separated by NUL byte instead of newline
+    #[arg(long, default_value_t = false)]
+    null: bool,
+    /// Do not show progress bar and warnings about skipped files or failed cache writes.
+    /// Fatal errors are still printed
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+    /// Show progress of bytes counted with estimated remaining time, also for small number of
+    /// files. Sizes of all matched files are read before counting. With `--cache-file` files
+    /// are counted instead of bytes
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+    /// Level of diagnostic log printed to stderr, `debug` explains skipped files and
+    /// tokenizer resolution
+    #[arg(long, value_enum, default_value_t = LogLevel::Warn)]
+    log_level: LogLevel,
+    /// Detect encoding of non UTF-8 files and decode them instead of skipping
+    #[arg(long, default_value_t = false)]
+    encoding_detect: bool,
+    /// Convert CRLF line endings to LF before counting
+    #[arg(long, default_value_t = false)]
+    normalize_newlines: bool,
+    /// Also print counts of Hugging Face tokenizer with padding and truncation of its
+    /// config, by default they are disabled to count all tokens
+    #[arg(long, default_value_t = false)]
+    respect_tokenizer_config: bool,
+    /// Count special tokens added by model template, like [CLS] and [SEP]
+    #[arg(long, default_value_t = false)]
+    special_tokens: bool,
     /// Files to count tokens
     #[arg()]
     files: Vec<String>,
@@ -65,90 +539,1425 @@ fn read_pipe() -> String {
     buffer.trim().to_string()
 }
 
-fn get_tokenizer(args: &Arguments) -> Result<Tokenizer> {
-    if args.identifier.is_some() && args.json_config.is_some() {
-        panic!("Both identifier and file options are presented. Only one can be used")
+/// Offline mode is enabled by flag or by `HF_HUB_OFFLINE` set to `1` or `true`
+fn is_offline(args: &Arguments) -> bool {
+    args.offline
+        || env::var(HF_HUB_OFFLINE_VAR)
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+/// Loads tokenizer from local cache or downloads it from huggingface hub
+fn load_pretrained(args: &Arguments, model_name: &str, revision: &str) -> Result<Tokenizer> {
+    // Identifier can be a local model directory, it is never fetched from the hub
+    let model_dir = Path::new(model_name);
+    if model_dir.is_dir() {
+        debug!("Loading tokenizer from model directory {model_name}");
+        let tokenizer_file = model_dir.join(TOKENIZER_FILE);
+        if !tokenizer_file.is_file() {
+            return Err(
+                format!("Model directory {model_name} does not contain {TOKENIZER_FILE}").into(),
+            );
+        }
+        return Tokenizer::from_file(tokenizer_file);
     }
-    if let Some(model_name) = &args.identifier {
-        let params = FromPretrainedParameters {
-            revision: args.revision.clone(),
-            user_agent: HashMap::new(),
-            token: args.token.clone(),
+    let cache = if args.no_cache {
+        None
+    } else {
+        TokenizerCache::from_env()
+    };
+    if let Some(tokenizer) = cache
+        .as_ref()
+        .and_then(|cache| cache.load(model_name, revision))
+    {
+        debug!("Loaded tokenizer {model_name} (revision {revision}) from cache");
+        return Ok(tokenizer);
+    }
+    if is_offline(args) {
+        return Err(format!(
+            "Tokenizer {model_name} (revision {revision}) is not available locally and offline \
+             mode is enabled. Use --json-config, a local model directory or download it to cache \
+             first"
+        )
+        .into());
+    }
+    // Parallel processes wait for the one downloading and then read its cached file
+    let lock = match cache.as_ref().map(|cache| cache.lock(model_name, revision)) {
+        Some(Ok(lock)) => Some(lock),
+        Some(Err(e)) => {
+            debug!("Cannot lock tokenizer cache, downloading without lock: {e}");
+            None
+        }
+        None => None,
+    };
+    if lock.is_some()
+        && let Some(tokenizer) = cache
+            .as_ref()
+            .and_then(|cache| cache.load(model_name, revision))
+    {
+        debug!("Loaded tokenizer {model_name} (revision {revision}) downloaded by another process");
+        return Ok(tokenizer);
+    }
+    debug!("Downloading tokenizer {model_name} (revision {revision})");
+    let tokenizer = download_tokenizer(args, model_name, revision)?;
+    if let Some(cache) = cache
+        && let Err(e) = cache.store(model_name, revision, &tokenizer)
+        && !args.quiet
+    {
+        eprintln!(
+            "Cannot save tokenizer to cache {}: {e}",
+            cache.dir().display()
+        );
+    }
+    Ok(tokenizer)
+}
+
+/// How failed download should be handled
+#[derive(Debug, PartialEq, Eq)]
+#[cfg(feature = "hub")]
+enum DownloadFailure {
+    /// Hub rejected credentials, retrying does not help
+    Unauthorized,
+    /// Network error, server error or rate limit
+    Transient,
+    /// Missing model, invalid identifier and other errors
+    Permanent,
+}
+
+#[cfg(feature = "hub")]
+impl DownloadFailure {
+    fn of(error: &tokencount::Error) -> Self {
+        let message = error.to_string();
+        // Hub responses are reported by http client as `<url>: status code <code>`
+        let status = message
+            .rsplit_once("status code ")
+            .and_then(|(_, code)| code.get(..3))
+            .and_then(|code| code.parse::<u16>().ok());
+        match status {
+            Some(401 | 403) => DownloadFailure::Unauthorized,
+            Some(429) => DownloadFailure::Transient,
+            Some(status) if status < 500 => DownloadFailure::Permanent,
+            Some(_) => DownloadFailure::Transient,
+            None if message.starts_with("request error") || message.starts_with("I/O error") => {
+                DownloadFailure::Transient
+            }
+            None => DownloadFailure::Permanent,
+        }
+    }
+}
+
+/// Huggingface token from `--token-file`, then `HF_TOKEN` and `HUGGING_FACE_HUB_TOKEN`
+/// environment variables, then `--token`. Surrounding whitespace is trimmed.
+#[cfg(feature = "hub")]
+fn hub_token(args: &Arguments) -> Result<Option<String>> {
+    if let Some(token_file) = &args.token_file {
+        let token = std::fs::read_to_string(token_file)
+            .map_err(|e| format!("Cannot read token file {token_file}: {e}"))?;
+        return Ok(Some(token.trim().to_string()));
+    }
+    let token = HF_TOKEN_VARS
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .map(|token| token.trim().to_string())
+        .find(|token| !token.is_empty());
+    Ok(token.or_else(|| args.token.clone()))
+}
+
+/// Downloads tokenizer from the hub, transient failures are retried with exponential backoff
+#[cfg(feature = "hub")]
+fn download_tokenizer(args: &Arguments, model_name: &str, revision: &str) -> Result<Tokenizer> {
+    let token = hub_token(args)?;
+    let has_token = token.is_some();
+    let params = FromPretrainedParameters {
+        revision: revision.to_string(),
+        user_agent: HashMap::new(),
+        token,
+    };
+    let mut delay = DOWNLOAD_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let error = match Tokenizer::from_pretrained(model_name, Some(params.clone())) {
+            Ok(tokenizer) => return Ok(tokenizer),
+            Err(error) => error,
         };
-        Tokenizer::from_pretrained(model_name, Some(params))
-    } else if let Some(json_config) = &args.json_config {
-        Tokenizer::from_file(json_config)
+        let failure = DownloadFailure::of(&error);
+        debug!("Download of tokenizer {model_name} failed, classified as {failure:?}: {error}");
+        match failure {
+            DownloadFailure::Unauthorized => {
+                let hint = if has_token {
+                    "check that the token has access to it"
+                } else {
+                    "pass a Hugging Face token with --token-file or HF_TOKEN"
+                };
+                return Err(
+                    format!("Access to tokenizer {model_name} denied, {hint}: {error}").into(),
+                );
+            }
+            DownloadFailure::Transient if attempt < args.download_retries => {
+                attempt += 1;
+                if !args.quiet {
+                    eprintln!(
+                        "Download of tokenizer {model_name} failed, retry {attempt}/{} in {}s: {error}",
+                        args.download_retries,
+                        delay.as_secs_f64()
+                    );
+                }
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+/// Built without hub support, only local tokenizers can be used
+#[cfg(not(feature = "hub"))]
+fn download_tokenizer(_args: &Arguments, model_name: &str, revision: &str) -> Result<Tokenizer> {
+    Err(format!(
+        "Tokenizer {model_name} (revision {revision}) is not available locally and tokencount \
+         is built without hub support. Use --json-config, a local model directory or rebuild \
+         with feature hub"
+    )
+    .into())
+}
+
+/// Replaces `\n`, `\t` and `\\` escapes with characters
+fn unescape(value: &str) -> std::result::Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some('t')) => unescaped.push('\t'),
+            ('\\', Some('\\')) => unescaped.push('\\'),
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    Ok(unescaped)
+}
+
+/// Parses fraction between 0 and 1
+fn parse_ratio(value: &str) -> std::result::Result<f64, String> {
+    let ratio: f64 = value
+        .parse()
+        .map_err(|_| format!("Invalid number {value}"))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("Value {value} is not between 0 and 1"))
+    }
+}
+
+/// Reads list of paths from file or from stdin if source is `-`
+fn read_files_from(source: &str, null: bool) -> std::io::Result<Vec<String>> {
+    let content = if source == "-" {
+        let mut buffer = String::new();
+        std::io::stdin().read_to_string(&mut buffer)?;
+        buffer
+    } else {
+        std::fs::read_to_string(source)?
+    };
+    Ok(parse_path_list(&content, null))
+}
+
+/// Progress bar on stderr, hidden for small number of files or if stderr is not a terminal
+fn progress_bar(total: usize, quiet: bool) -> ProgressBar {
+    if quiet || total < PROGRESS_MIN_FILES || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(total as u64);
+    if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} files {elapsed}") {
+        bar.set_style(style);
+    }
+    bar
+}
+
+/// Progress bar of bytes on stderr with estimated remaining time, hidden if stderr is not
+/// a terminal
+fn byte_progress_bar(total_bytes: u64, quiet: bool) -> ProgressBar {
+    if quiet || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(total_bytes);
+    if let Ok(style) = ProgressStyle::with_template(
+        "{bar:40} {bytes}/{total_bytes} {binary_bytes_per_sec} {elapsed} ETA {eta}",
+    ) {
+        bar.set_style(style);
+    }
+    bar
+}
+
+/// Sizes of files for progress, archive members and unreadable files have size 0
+fn file_sizes(files: &[PathBuf]) -> HashMap<&PathBuf, u64> {
+    files
+        .iter()
+        .map(|file| (file, std::fs::metadata(file).map_or(0, |meta| meta.len())))
+        .collect()
+}
+
+/// Tokenizer backend with name printed in output and identity pinned in lockfile
+struct NamedCounter {
+    name: String,
+    /// Identifier in lockfile, name of tokenizer compared at several revisions is
+    /// `identifier@revision`
+    identifier: String,
+    /// Hub revision, `None` for tokenizers not downloaded from the hub
+    revision: Option<String>,
+    backend: Box<dyn CountTokens>,
+}
+
+impl NamedCounter {
+    /// Counter of tokenizer not downloaded from the hub, identified by its name
+    fn local(name: String, backend: Box<dyn CountTokens>) -> Self {
+        NamedCounter {
+            identifier: name.clone(),
+            name,
+            revision: None,
+            backend,
+        }
+    }
+}
+
+/// Loads tokenizer JSON config, configs with `.gz` or `.bz2` extension are decompressed
+/// in memory
+fn read_tokenizer_file(path: &str) -> Result<Tokenizer> {
+    let file = File::open(path).map_err(|e| format!("Cannot open {path}: {e}"))?;
+    let mut reader: Box<dyn Read> = match Path::new(path).extension() {
+        Some(extension) if extension == "gz" => Box::new(MultiGzDecoder::new(file)),
+        Some(extension) if extension == "bz2" => Box::new(MultiBzDecoder::new(file)),
+        _ => return Tokenizer::from_file(path),
+    };
+    debug!("Decompressing tokenizer config {path}");
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Cannot decompress {path}: {e}"))?;
+    Tokenizer::from_bytes(bytes)
+}
+
+fn get_tokenizer(args: &Arguments) -> Result<Vec<NamedCounter>> {
+    if let Some(command) = &args.tokenizer_cmd {
+        debug!("Using tokenizer command {command}");
+        check_single_revision(args, command)?;
+        return Ok(vec![NamedCounter::local(
+            command.clone(),
+            Box::new(CommandCounter::new(command)?),
+        )]);
+    }
+    if let Some(encoding) = args.encoding {
+        let name = encoding
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        debug!("Using tiktoken encoding {name}");
+        check_single_revision(args, &name)?;
+        return Ok(vec![NamedCounter::local(
+            name,
+            Box::new(TiktokenCounter::new(encoding)?),
+        )]);
+    }
+    if let Some(json_config) = &args.json_config {
+        debug!("Loading tokenizer from json config {json_config}");
+        check_single_revision(args, json_config)?;
+        let tokenizer = read_tokenizer_file(json_config)?;
+        return tokenizer_counters(args, json_config, json_config, None, tokenizer);
+    }
+    let models = if !args.identifier.is_empty() {
+        args.identifier.clone()
     } else if let Ok(file_path) = env::var(TOKEN_COUNT_FILE_VAR) {
-        Tokenizer::from_file(file_path)
+        debug!("Loading tokenizer from json config {file_path} of {TOKEN_COUNT_FILE_VAR}");
+        check_single_revision(args, &file_path)?;
+        let tokenizer = read_tokenizer_file(&file_path)?;
+        return tokenizer_counters(args, &file_path, &file_path, None, tokenizer);
     } else {
-        let tokenizer_model =
-            env::var(TOKEN_COUNT_MODEL_VAR).unwrap_or(DEFAULT_TOKENIZER.to_string());
-        let params = FromPretrainedParameters {
-            revision: args.revision.clone(),
-            user_agent: HashMap::new(),
-            token: args.token.clone(),
+        let model = env::var(TOKEN_COUNT_MODEL_VAR).unwrap_or(DEFAULT_TOKENIZER.to_string());
+        debug!("No tokenizer passed, using {model}");
+        vec![model]
+    };
+    let mut counters = Vec::new();
+    for (model_name, revision) in model_revisions(&models, &args.revision)? {
+        // Local model directory is loaded as is, it has no revisions
+        let from_hub = !Path::new(&model_name).is_dir();
+        if !from_hub {
+            check_single_revision(args, &model_name)?;
+        }
+        let tokenizer = load_pretrained(args, &model_name, &revision)?;
+        // Revision is shown only when several are compared
+        let name = if args.revision.len() > 1 {
+            format!("{model_name}@{revision}")
+        } else {
+            model_name.clone()
         };
-        Tokenizer::from_pretrained(tokenizer_model, Some(params))
+        let revision = from_hub.then_some(revision.as_str());
+        counters.extend(tokenizer_counters(
+            args,
+            &name,
+            &model_name,
+            revision,
+            tokenizer,
+        )?);
     }
+    Ok(counters)
 }
 
-fn main() {
-    let args = Arguments::parse();
-    let tokenizer = get_tokenizer(&args).expect("Failed to initialize tokenizer");
-    let stdin = std::io::stdin();
-    if stdin.is_terminal() {
-        // Standard use
-        let config = FileMatchConfig::new(
-            args.recursive || args.dereference_recursive,
-            args.dereference_recursive,
-            args.include,
-            args.exclude,
-            args.exclude_dir,
+/// Several revisions are compared only for models downloaded from the hub, `source` is
+/// tokenizer which has no revisions
+fn check_single_revision(args: &Arguments, source: &str) -> Result<()> {
+    if args.revision.len() > 1 {
+        return Err(format!(
+            "{source} has no revisions, multiple --revision values can be used only with models \
+             from the hub"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Tokenizer without padding and truncation counts all tokens of text. With
+/// `--respect-tokenizer-config` tokenizer with loaded padding and truncation is added to
+/// show count seen by model.
+fn tokenizer_counters(
+    args: &Arguments,
+    name: &str,
+    identifier: &str,
+    revision: Option<&str>,
+    tokenizer: Tokenizer,
+) -> Result<Vec<NamedCounter>> {
+    let mut raw = tokenizer.clone();
+    raw.with_truncation(None)?.with_padding(None);
+    let mut counters = vec![NamedCounter {
+        name: name.to_string(),
+        identifier: identifier.to_string(),
+        revision: revision.map(str::to_string),
+        backend: Box::new(raw),
+    }];
+    if args.respect_tokenizer_config {
+        // Truncation and padding are applied to count of each file, not of each window
+        let configured = ConfiguredTokenizer::new(tokenizer)?;
+        counters.push(NamedCounter {
+            name: format!("{name} {CONFIGURED_SUFFIX}"),
+            identifier: format!("{identifier} {CONFIGURED_SUFFIX}"),
+            revision: revision.map(str::to_string),
+            backend: Box::new(configured),
+        });
+    }
+    Ok(counters)
+}
+
+/// Writes used tokenizers to lockfile with `--lock`, with `--locked` exits if they differ
+/// from locked ones
+fn check_lockfile(args: &Arguments, tokenizers: &[NamedCounter]) {
+    let resolved = Lockfile {
+        tokenizers: tokenizers
+            .iter()
+            .map(|tokenizer| LockedTokenizer {
+                identifier: tokenizer.identifier.clone(),
+                revision: tokenizer.revision.clone(),
+                hash: tokenizer.backend.fingerprint(),
+            })
+            .collect(),
+    };
+    let path = Path::new(LOCK_FILE);
+    let result = if args.lock {
+        resolved.save(path)
+    } else {
+        Lockfile::load(path).and_then(|locked| locked.verify(&resolved))
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Explains which filters could eliminate all files
+fn no_matches_message(args: &Arguments) -> String {
+    let mut filters = Vec::new();
+    for (name, patterns) in [
+        ("--ext", &args.ext),
+        ("--include", &args.include),
+        ("--exclude", &args.exclude),
+        ("--exclude-dir", &args.exclude_dir),
+    ] {
+        if !patterns.is_empty() {
+            filters.push(format!("{name} {}", patterns.join(", ")));
+        }
+    }
+    if args.gitignore {
+        filters.push("--gitignore".to_string());
+    }
+    if args.invert {
+        filters.push("--invert".to_string());
+    }
+    if let Some(max_depth) = args.max_depth {
+        filters.push(format!("--max-depth {max_depth}"));
+    }
+    if filters.is_empty() {
+        "No files matched".to_string()
+    } else {
+        format!("No files matched after filtering by {}", filters.join("; "))
+    }
+}
+
+/// Reads stdin until EOF and prints token count of each line, or of each paragraph
+/// terminated by blank line in multiline mode. Counts of all tokenizers are printed.
+fn run_repl(counter: &TokenCounter, multiline: bool) {
+    let interactive = std::io::stdin().is_terminal();
+    let prompt = || {
+        if interactive {
+            eprint!("> ");
+            std::io::stderr()
+                .flush()
+                .expect("Error while writing prompt");
+        }
+    };
+    let print_count = |text: &str| {
+        let count = counter.count_text(PathBuf::new(), text);
+        let values: Vec<String> = std::iter::once(count.tokens)
+            .chain(count.other_tokens)
+            .map(|tokens| tokens.to_string())
+            .collect();
+        println!("{}", values.join(" "));
+    };
+    let mut paragraph: Vec<String> = Vec::new();
+    prompt();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("Error while reading stdin");
+        if !multiline {
+            print_count(&line);
+            prompt();
+        } else if !line.is_empty() {
+            paragraph.push(line);
+        } else if !paragraph.is_empty() {
+            print_count(&paragraph.join("\n"));
+            paragraph.clear();
+            prompt();
+        }
+    }
+    if !paragraph.is_empty() {
+        print_count(&paragraph.join("\n"));
+    }
+}
+
+/// Output file created or truncated at `path`, stdout if path is not set
+fn open_output(path: Option<&str>) -> Box<dyn Write + Send> {
+    match path {
+        Some(path) => {
+            let file = File::create(path).unwrap_or_else(|e| {
+                eprintln!("Cannot create output file {path}: {e}");
+                std::process::exit(1);
+            });
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout()),
+    }
+}
+
+/// Checks if text output should be colorized, `NO_COLOR` disables automatic colors
+fn use_color(args: &Arguments) -> bool {
+    match args.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            args.output.is_none()
+                && std::io::stdout().is_terminal()
+                && env::var_os(NO_COLOR_VAR).is_none_or(|value| value.is_empty())
+        }
+    }
+}
+
+/// Directory which printed paths are relative to, `None` if paths are printed as given
+fn relative_root(args: &Arguments, matched_files: &[PathBuf]) -> Option<PathBuf> {
+    match args.relative_to.as_ref()? {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None if args.concat => None,
+        None => common_ancestor(matched_files),
+    }
+}
+
+/// Resolves files to count from arguments, `--files-from` list and changed files of
+/// `--git-diff`, which replace arguments. Exits if paths are invalid or nothing matched,
+/// empty diff is not an error.
+fn match_files(args: &Arguments) -> Vec<PathBuf> {
+    let mut files = match &args.git_diff {
+        // Paths limit changed files and are not walked
+        Some(reference) => {
+            git_changed_files(reference.as_deref(), &args.files).unwrap_or_else(|e| {
+                eprintln!("Cannot list changed files: {e}");
+                std::process::exit(1);
+            })
+        }
+        None => args.files.clone(),
+    };
+    let empty_diff = args.git_diff.is_some() && files.is_empty();
+    if let Some(source) = &args.files_from {
+        let listed_files = read_files_from(source, args.null).unwrap_or_else(|e| {
+            eprintln!("Cannot read list of files from {source}: {e}");
+            std::process::exit(1);
+        });
+        files.extend(listed_files);
+    }
+    let config = file_match_config(args, args.recursive || args.dereference_recursive);
+    let matched_files = get_matched_files(files, config).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    if matched_files.is_empty() && !args.allow_empty && !empty_diff {
+        eprintln!("{}", no_matches_message(args));
+        std::process::exit(NO_MATCHES_EXIT_CODE);
+    }
+    matched_files
+}
+
+fn file_match_config(args: &Arguments, recursive: bool) -> FileMatchConfig {
+    FileMatchConfig::new(
+        recursive,
+        args.dereference_recursive || args.follow_symlinks,
+        args.include.clone(),
+        args.exclude.clone(),
+        args.exclude_dir.clone(),
+    )
+    .with_gitignore(args.gitignore)
+    .with_ignore_file(!args.no_ignore_file)
+    .with_max_depth(args.max_depth)
+    .with_ignore_case(args.ignore_case)
+    .with_hidden(args.hidden)
+    .with_invert(args.invert)
+    .with_order(args.order)
+    .with_extensions(args.ext.clone())
+}
+
+/// Describes how tokenizer is selected, e.g. option or environment variable, following
+/// precedence of `get_tokenizer`
+fn tokenizer_source(args: &Arguments) -> String {
+    if args.count_mode != CountMode::Tokens {
+        "--count-mode".to_string()
+    } else if args.tokenizer_cmd.is_some() {
+        "--tokenizer-cmd".to_string()
+    } else if args.encoding.is_some() {
+        "--encoding".to_string()
+    } else if args.json_config.is_some() {
+        "--json-config".to_string()
+    } else if !args.identifier.is_empty() {
+        "--identifier".to_string()
+    } else if env::var(TOKEN_COUNT_FILE_VAR).is_ok() {
+        format!("{TOKEN_COUNT_FILE_VAR} environment variable")
+    } else if env::var(TOKEN_COUNT_MODEL_VAR).is_ok() {
+        format!("{TOKEN_COUNT_MODEL_VAR} environment variable")
+    } else {
+        "default".to_string()
+    }
+}
+
+/// Describes tokenizers and options which affect counts, cached counts are valid only
+/// for the same identity. Tokenizers are identified by fingerprint of their definition, so
+/// changed tokenizer file invalidates cache, backends without definition by name.
+fn tokenizer_identity(args: &Arguments, tokenizers: &[NamedCounter]) -> String {
+    let tokenizer: Vec<String> = tokenizers
+        .iter()
+        .map(|tokenizer| {
+            tokenizer
+                .backend
+                .fingerprint()
+                .unwrap_or_else(|| tokenizer.name.clone())
+        })
+        .collect();
+    format!(
+        "{};special_tokens={};encoding_detect={};normalize_newlines={};\
+         respect_tokenizer_config={};head_tokens={:?};chat_template={:?};vars={:?};\
+         stream_threshold={}",
+        tokenizer.join(","),
+        args.special_tokens,
+        args.encoding_detect,
+        args.normalize_newlines,
+        args.respect_tokenizer_config,
+        args.head_tokens,
+        args.chat_template,
+        args.vars,
+        args.stream_threshold
+    )
+}
+
+fn group_by(args: &Arguments) -> Option<GroupBy> {
+    if args.by_extension {
+        Some(GroupBy::Extension)
+    } else if args.group_by_dir {
+        Some(GroupBy::Directory)
+    } else {
+        args.group_by_regex.clone().map(GroupBy::Regex)
+    }
+}
+
+/// Checks counts against threshold and prints to stderr what exceeded it
+fn exceeds_threshold(counts: &[FileCount], total: u64, threshold: usize, verbose: bool) -> bool {
+    if verbose {
+        let exceeded: Vec<&FileCount> = counts
+            .iter()
+            .filter(|count| count.tokens > threshold)
+            .collect();
+        for count in &exceeded {
+            eprintln!(
+                "{} has {} tokens, exceeds threshold {threshold}",
+                count.get_path_string(),
+                count.tokens
+            );
+        }
+        !exceeded.is_empty()
+    } else if total > threshold as u64 {
+        eprintln!("Total token count {total} exceeds threshold {threshold}");
+        true
+    } else {
+        false
+    }
+}
+
+/// Fills flags not passed on command line from config file
+fn apply_config(args: &mut Arguments) {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None if Path::new(CONFIG_FILE).is_file() => PathBuf::from(CONFIG_FILE),
+        None => return,
+    };
+    let config = Config::load(&path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    args.recursive |= config.recursive.unwrap_or(false);
+    let tokenizer_passed = !args.identifier.is_empty()
+        || args.json_config.is_some()
+        || args.encoding.is_some()
+        || args.tokenizer_cmd.is_some();
+    if !tokenizer_passed {
+        args.identifier = config.model;
+    }
+    for (flag, defaults) in [
+        (&mut args.include, config.include),
+        (&mut args.exclude, config.exclude),
+        (&mut args.exclude_dir, config.exclude_dir),
+    ] {
+        if flag.is_empty() {
+            *flag = defaults;
+        }
+    }
+}
+
+/// Text snippet printed instead of path in verbose output, line breaks are escaped and
+/// long texts are shortened
+fn text_label(text: &str) -> String {
+    let escaped: String = text.escape_debug().collect();
+    if escaped.chars().count() <= TEXT_LABEL_LENGTH {
+        return escaped;
+    }
+    let shortened: String = escaped.chars().take(TEXT_LABEL_LENGTH).collect();
+    format!("{shortened}...")
+}
+
+/// Prints durations of phases to stderr in seconds
+fn print_timing(phases: &[(&str, Duration)]) {
+    let phases: Vec<String> = phases
+        .iter()
+        .map(|(name, duration)| format!("{name}={:.3}s", duration.as_secs_f64()))
+        .collect();
+    eprintln!("timing: {}", phases.join(" "));
+}
+
+/// Reads stdin line by line and counts tokens of each line, lines are encoded by batches
+fn count_stdin_lines(counter: &TokenCounter, name: &str) -> Vec<FileCount> {
+    let mut counts = Vec::new();
+    let mut batch = Vec::with_capacity(STDIN_LINES_BATCH);
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        let line = lines.next().transpose().unwrap_or_else(|e| {
+            eprintln!("Error while reading stdin: {e}");
+            std::process::exit(1);
+        });
+        let is_end = line.is_none();
+        batch.extend(line);
+        if batch.len() == STDIN_LINES_BATCH || (is_end && !batch.is_empty()) {
+            counts.extend(counter.count_text_lines(name, &batch, counts.len() + 1));
+            batch.clear();
+        }
+        if is_end {
+            return counts;
+        }
+    }
+}
+
+/// Prints number and paths of files without tokens to stderr
+fn print_empty_files(counts: &[FileCount]) {
+    let empty: Vec<&FileCount> = counts.iter().filter(|count| count.tokens == 0).collect();
+    eprintln!("{} files without tokens", empty.len());
+    for count in empty {
+        eprintln!("  {}", count.get_path_string());
+    }
+}
+
+/// Prints tokens of the only input with byte offsets, exits if there are several inputs.
+/// File and stdin are not normalized, so offsets point into their original bytes.
+fn print_offsets(
+    args: &Arguments,
+    backend: &dyn CountTokens,
+    matched_files: Option<&[PathBuf]>,
+    out: &mut dyn Write,
+) {
+    let inputs = matched_files.map_or(args.text.len(), <[PathBuf]>::len);
+    let text = match (matched_files, args.text.as_slice()) {
+        (Some([file]), _) => std::fs::read_to_string(file).unwrap_or_else(|e| {
+            eprintln!("Cannot read {} as UTF-8 text: {e}", file.display());
+            std::process::exit(1);
+        }),
+        (None, [text]) => text.clone(),
+        (None, []) => {
+            let mut data = String::new();
+            std::io::stdin()
+                .read_to_string(&mut data)
+                .expect("Error while read data from pipe");
+            data
+        }
+        _ => {
+            eprintln!("--offsets requires a single file or text, got {inputs}");
+            std::process::exit(1);
+        }
+    };
+    let tokens = backend
+        .offsets(&text, args.special_tokens)
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot get token offsets: {e}");
+            std::process::exit(1);
+        });
+    write!(out, "{}", offsets(&tokens, args.format))
+        .and_then(|_| out.flush())
+        .expect("Error while writing output");
+}
+
+/// Counts `--byte-range` of a single matched file, exits if there are more files or the
+/// range is not text
+fn count_byte_range(counter: &TokenCounter, files: &[PathBuf], range: ByteRange) -> FileCount {
+    let [file] = files else {
+        eprintln!("--byte-range requires a single file, got {}", files.len());
+        std::process::exit(1);
+    };
+    let text = read_byte_range(file, range).unwrap_or_else(|e| {
+        eprintln!(
+            "Cannot read byte range of {} as UTF-8 text: {e}",
+            file.display()
         );
-        let mut token_count: u64 = 0;
-        for file_chunk in get_matched_files(args.files, config).chunks(FILE_CHUNK_SIZE) {
-            let file_contents: Vec<FileContent> = file_chunk
-                .iter()
-                .map(|file| FileContent::new(file.clone()))
-                .filter(|file| file.is_text_file())
-                .collect();
-            let files_names: Vec<String> = file_contents
-                .iter()
-                .map(|file| file.get_path_string())
-                .collect();
-            let data: Vec<String> = file_contents
-                .iter()
-                .map(|file| file.read_content())
-                .collect();
-            let lengths: Vec<usize> = tokenizer
-                .encode_batch(data, false)
-                .map(|vec| vec.iter().map(|enc| enc.len()).collect())
-                .expect("Error while encoding text");
-            if args.verbose {
-                for (file_name, length) in files_names.iter().zip(lengths.iter()) {
-                    println!("{file_name} {length}");
-                }
-            } else {
-                for length in lengths {
-                    token_count += length as u64;
+        std::process::exit(1);
+    });
+    counter.count_text(file.clone(), &text)
+}
+
+/// Loads chat template selected by `--chat-template`, exits if it is invalid
+fn chat_template(args: &Arguments) -> Option<ChatTemplate> {
+    let source = args.chat_template.as_ref()?;
+    let template = if source == CHAT_TEMPLATE_AUTO {
+        // Tokenizer config is stored next to tokenizer.json of local models
+        let config = args
+            .identifier
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.is_dir())
+            .chain(args.json_config.iter().map(|path| {
+                Path::new(path)
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default()
+            }))
+            .map(|dir| dir.join(TOKENIZER_CONFIG_FILE))
+            .find(|config| config.is_file());
+        match config.map(|config| ChatTemplate::from_tokenizer_config(&config)) {
+            Some(Ok(Some(template))) => Ok(template),
+            Some(Err(e)) => Err(e),
+            _ => {
+                if !args.quiet {
+                    eprintln!("No chat template found for tokenizer, counting without it");
                 }
+                return None;
             }
         }
-        if !args.verbose {
-            println!("{token_count}");
+    } else {
+        ChatTemplate::load(Path::new(source))
+    };
+    // Templates are rendered while counting, errors of rendering are reported before it
+    match template.and_then(|template| template.apply("").map(|_| template)) {
+        Ok(template) => Some(template),
+        Err(e) => {
+            eprintln!("Invalid chat template: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Sets `interrupted` on the first Ctrl-C, so counts of files processed so far are printed.
+/// The second Ctrl-C exits immediately.
+fn install_interrupt_handler(interrupted: Arc<AtomicBool>, quiet: bool) {
+    let result = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::Relaxed) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
         }
+    });
+    if let Err(e) = result
+        && !quiet
+    {
+        eprintln!("Cannot handle interruption, Ctrl-C discards counts: {e}");
+    }
+}
+
+/// Paths to watch for changes: existing path arguments, archives of member arguments and
+/// files listed with `--files-from`
+fn watched_paths(args: &Arguments, matched_files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = args
+        .files
+        .iter()
+        .map(PathBuf::from)
+        .filter_map(|path| match ArchiveMember::parse(&path) {
+            Some(member) => Some(member.archive),
+            None => path.exists().then_some(path),
+        })
+        .collect();
+    if args.files_from.is_some() || args.git_diff.is_some() {
+        paths.extend(matched_files.iter().filter(|path| path.exists()).cloned());
+    }
+    paths
+}
+
+/// Recounts matched files with already loaded tokenizer after changes settle for
+/// `WATCH_DEBOUNCE`. Returns when watcher stops.
+fn run_watch(
+    args: &Arguments,
+    counter: &TokenCounter,
+    mut matched_files: Vec<PathBuf>,
+    out: &mut dyn Write,
+) {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).unwrap_or_else(|e| {
+        eprintln!("Cannot watch files: {e}");
+        std::process::exit(1);
+    });
+    let mode = if args.recursive || args.dereference_recursive {
+        RecursiveMode::Recursive
     } else {
-        // Pipe
-        let data = read_pipe();
-        let token_count = tokenizer
-            .encode(data, false)
-            .map(|enc| enc.len())
-            .expect("Error while encoding text");
-        let result = if args.verbose {
-            format!(". {token_count}")
+        RecursiveMode::NonRecursive
+    };
+    for path in watched_paths(args, &matched_files) {
+        if let Err(e) = watcher.watch(&path, mode) {
+            eprintln!("Cannot watch {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+    let absolute = |files: &[PathBuf]| -> Vec<PathBuf> {
+        files
+            .iter()
+            .filter_map(|file| std::path::absolute(file).ok())
+            .collect()
+    };
+    // Writing updated total must not trigger another recount
+    let output = args
+        .output
+        .as_ref()
+        .and_then(|output| std::path::absolute(output).ok());
+    while let Ok(event) = receiver.recv() {
+        // Collect events until no change is reported during debounce period
+        let mut events = vec![event];
+        while let Ok(event) = receiver.recv_timeout(WATCH_DEBOUNCE) {
+            events.push(event);
+        }
+        let changed: Vec<PathBuf> = events
+            .into_iter()
+            .filter_map(|event: notify::Result<notify::Event>| event.ok())
+            .filter(|event| !event.kind.is_access())
+            .flat_map(|event| event.paths)
+            .filter(|path| Some(path) != output.as_ref())
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+        let files = match_files(args);
+        let watched = absolute(&matched_files);
+        if files == matched_files && !changed.iter().any(|path| watched.contains(path)) {
+            // Changes of files which are not counted, e.g. output file
+            continue;
+        }
+        matched_files = files;
+        let counts = if args.concat {
+            let label = PathBuf::from(CONCAT_LABEL);
+            vec![counter.count_concatenated(&matched_files, &args.concat_separator, label)]
+        } else if args.count_paths {
+            counter.count_path_names(&matched_files)
         } else {
-            format!("{token_count}")
+            counter.count_paths(&matched_files)
+        };
+        let total: usize = counts.iter().map(|count| count.tokens).sum();
+        writeln!(out, "{total}")
+            .and_then(|_| out.flush())
+            .expect("Error while writing output");
+    }
+}
+
+/// Counts files of both `--compare` trees recursively with paths relative to the roots and
+/// prints their difference
+fn run_compare(args: &Arguments, counter: &TokenCounter, out: &mut dyn Write) {
+    let count_tree = |root: &str| {
+        let files = get_matched_files(vec![root.to_string()], file_match_config(args, true))
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+        let mut counts = counter.count_paths(&files);
+        for count in &mut counts {
+            count.path = relative_path(&count.path, Path::new(root));
+        }
+        counts
+    };
+    let before = count_tree(&args.compare[0]);
+    let after = count_tree(&args.compare[1]);
+    let total = |counts: &[FileCount]| counts.iter().map(|count| count.tokens).sum::<usize>();
+    let (before_total, after_total) = (total(&before), total(&after));
+    let deltas = compare_counts(before, after);
+    write!(
+        out,
+        "{}",
+        comparison(
+            &deltas,
+            before_total,
+            after_total,
+            args.verbose,
+            args.format
+        )
+    )
+    .and_then(|_| out.flush())
+    .expect("Error while writing output");
+    if counter.read_errors() > 0 {
+        eprintln!(
+            "{} files could not be read, comparison is partial",
+            counter.read_errors()
+        );
+        std::process::exit(READ_ERROR_EXIT_CODE);
+    }
+}
+
+/// Counts matched files, `--text` or synthetic corpus `runs` times and prints throughput.
+/// Corpus is counted by the same pipeline as without benchmark, e.g. with `--chunk-size`
+fn run_benchmark(
+    args: &Arguments,
+    counter: &TokenCounter,
+    tokenizer: &str,
+    matched_files: Option<&[PathBuf]>,
+    runs: usize,
+    out: &mut dyn Write,
+) {
+    let texts: Vec<String> = match matched_files {
+        Some(_) => Vec::new(),
+        None if !args.text.is_empty() => args.text.clone(),
+        None => (0..BENCHMARK_DOCUMENTS)
+            .map(|document| BENCHMARK_TEXT.repeat(1 + document % 8))
+            .collect(),
+    };
+    // Only counted files make throughput, skipped binary and unreadable files are excluded
+    let sizes = matched_files.map(file_sizes).unwrap_or_default();
+    let mut report = Benchmark {
+        tokenizer: tokenizer.to_string(),
+        ..Benchmark::default()
+    };
+    for _ in 0..runs {
+        let start = Instant::now();
+        let counts: Vec<FileCount> = match matched_files {
+            Some(files) => counter.count_paths(files),
+            None => texts
+                .par_iter()
+                .map(|text| counter.count_text(PathBuf::from(text_label(text)), text))
+                .collect(),
+        };
+        report.runs.push(start.elapsed());
+        if counter.is_interrupted() {
+            eprintln!("Interrupted, benchmark is incomplete");
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        report.files = counts.len();
+        report.tokens = counts.iter().map(|count| count.tokens).sum();
+        report.bytes = match matched_files {
+            Some(_) => counts
+                .iter()
+                .filter_map(|count| sizes.get(&count.path))
+                .sum(),
+            None => texts.iter().map(|text| text.len() as u64).sum(),
+        };
+    }
+    write!(out, "{}", benchmark(&report, args.format))
+        .and_then(|_| out.flush())
+        .expect("Error while writing output");
+}
+
+fn main() {
+    let start = Instant::now();
+    let mut args = Arguments::parse();
+    env_logger::Builder::new()
+        .filter_level(args.log_level.filter())
+        .init();
+    let cli_source = tokenizer_source(&args);
+    apply_config(&mut args);
+    if let Some(chunk_window) = args.chunk_window
+        && args.chunk_overlap >= chunk_window
+    {
+        eprintln!(
+            "--chunk-overlap {} must be less than --chunk-window {chunk_window}",
+            args.chunk_overlap
+        );
+        std::process::exit(1);
+    }
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .expect("Failed to initialize thread pool");
+    }
+    let is_file_mode =
+        std::io::stdin().is_terminal() || args.files_from.is_some() || args.git_diff.is_some();
+    // Benchmark without files uses synthetic corpus
+    let synthetic_benchmark = args.benchmark.is_some()
+        && args.files.is_empty()
+        && args.files_from.is_none()
+        && args.git_diff.is_none();
+    let mut matched_files = (is_file_mode
+        && !args.repl
+        && !args.tokenizer_info
+        && !synthetic_benchmark
+        && args.text.is_empty()
+        && args.compare.is_empty())
+    .then(|| match_files(&args));
+    if args.dedup
+        && let Some(files) = matched_files.take()
+    {
+        let (files, duplicates) = dedup_files(files);
+        if !args.quiet {
+            eprintln!("{duplicates} duplicate files skipped");
+        }
+        matched_files = Some(files);
+    }
+    if args.watch && matched_files.is_none() {
+        eprintln!("--watch requires files to count, stdin is not watched");
+        std::process::exit(1);
+    }
+    let discovery_time = start.elapsed();
+    let mut out = open_output(args.output.as_deref());
+    if args.list_only {
+        for file in matched_files.unwrap_or_default() {
+            writeln!(out, "{}", file.display()).expect("Error while writing output");
+        }
+        out.flush().expect("Error while writing output");
+        return;
+    }
+    let unit = args.count_mode.text_unit();
+    let tokenizer_start = Instant::now();
+    let tokenizers = match unit {
+        Some(unit) => vec![NamedCounter::local(args.count_mode.name(), Box::new(unit))],
+        None => get_tokenizer(&args).unwrap_or_else(|e| {
+            eprintln!("Failed to initialize tokenizer: {e}");
+            std::process::exit(1);
+        }),
+    };
+    if args.tokenizer_info {
+        // Model of config file is used only if no tokenizer was passed
+        let source = match tokenizer_source(&args) {
+            source if source == cli_source => source,
+            _ => format!("model of {CONFIG_FILE}"),
         };
-        println!("{result}");
+        let infos: Vec<TokenizerInfo> = tokenizers
+            .iter()
+            .map(|tokenizer| TokenizerInfo {
+                name: tokenizer.name.clone(),
+                source: source.clone(),
+                vocab_size: tokenizer.backend.vocab_size(),
+                special_tokens: tokenizer.backend.special_tokens(),
+                fingerprint: tokenizer.backend.fingerprint(),
+            })
+            .collect();
+        write!(out, "{}", tokenizer_info(&infos, args.format))
+            .and_then(|_| out.flush())
+            .expect("Error while writing output");
+        return;
+    }
+    if args.lock || args.locked {
+        check_lockfile(&args, &tokenizers);
+    }
+    let cache_identity = args
+        .cache_file
+        .is_some()
+        .then(|| tokenizer_identity(&args, &tokenizers));
+    let (tokenizer_names, mut backends): (Vec<String>, Vec<Box<dyn CountTokens>>) = tokenizers
+        .into_iter()
+        .map(|tokenizer| (tokenizer.name, tokenizer.backend))
+        .unzip();
+    let tokenizer_time = tokenizer_start.elapsed();
+    if args.offsets {
+        print_offsets(
+            &args,
+            backends[0].as_ref(),
+            matched_files.as_deref(),
+            &mut out,
+        );
+        return;
+    }
+    let mut counter = TokenCounter::from_boxed(backends.remove(0))
+        .with_stream_threshold(args.stream_threshold)
+        .with_encoding_detection(args.encoding_detect)
+        .with_newline_normalization(args.normalize_newlines)
+        .with_special_tokens(args.special_tokens)
+        .with_max_file_size(args.max_file_size)
+        .with_sample_size(args.sample_size as usize)
+        .with_binary_threshold(if args.strict_encoding {
+            0.0
+        } else {
+            args.binary_threshold
+        })
+        .with_quiet(args.quiet)
+        .with_chunk_size(args.chunk_size)
+        .with_head_tokens(args.head_tokens)
+        .with_chat_template(chat_template(&args))
+        .with_variables(
+            (!args.vars.is_empty()).then(|| Variables::new(args.vars.iter().cloned().collect())),
+        );
+    for backend in backends {
+        counter = counter.add_backend(backend);
+    }
+    if matched_files.is_some() && !args.watch {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        install_interrupt_handler(interrupted.clone(), args.quiet);
+        counter = counter.with_interrupt_flag(interrupted);
+    }
+    if args.repl {
+        run_repl(&counter, args.multiline);
+        return;
+    }
+    if !args.compare.is_empty() {
+        run_compare(&args, &counter, &mut out);
+        return;
+    }
+    if let Some(runs) = args.benchmark {
+        if tokenizer_names.len() > 1 {
+            eprintln!(
+                "--benchmark measures a single tokenizer, got {}",
+                tokenizer_names.len()
+            );
+            std::process::exit(1);
+        }
+        let corpus = matched_files.as_deref();
+        run_benchmark(&args, &counter, &tokenizer_names[0], corpus, runs, &mut out);
+        return;
+    }
+    let relative_root = matched_files
+        .as_ref()
+        .and_then(|matched_files| relative_root(&args, matched_files));
+    let relative = |path: PathBuf| match &relative_root {
+        Some(root) => relative_path(&path, root),
+        None => path,
+    };
+    let per_line =
+        args.per_line || (args.stdin_lines && matched_files.is_none() && args.text.is_empty());
+    let mut writer = OutputWriter::new(args.format, args.verbose || per_line)
+        .with_stats(args.stats)
+        .with_top(args.top)
+        .with_min_tokens(args.min_tokens)
+        .with_sort(args.sort)
+        .with_group_by(group_by(&args))
+        .with_unit(unit.is_some().then(|| tokenizer_names[0].clone()))
+        .with_tokenizer_names(tokenizer_names)
+        .with_context_window(args.context_window)
+        .with_chunking(args.chunk_window, args.chunk_overlap)
+        .with_budget(args.budget.map(|budget| budget as usize))
+        .with_template(args.template.clone())
+        .with_print0(args.print0)
+        .with_color(use_color(&args))
+        .with_highlight(args.threshold)
+        .with_separator(args.separator.clone())
+        .with_per_line(per_line);
+    let mut streamed = false;
+    let encoding_start = Instant::now();
+    let counts: Vec<FileCount> = if !args.text.is_empty() {
+        args.text
+            .iter()
+            .map(|text| {
+                let text = if args.normalize_newlines {
+                    normalize_newlines(text.clone())
+                } else {
+                    text.clone()
+                };
+                counter.count_text(PathBuf::from(text_label(&text)), &text)
+            })
+            .collect()
+    } else if let Some(matched_files) = &matched_files {
+        // Standard use
+        // Cache reports only number of files found in it
+        let sizes = (args.progress && args.cache_file.is_none()).then(|| file_sizes(matched_files));
+        let bar = match &sizes {
+            Some(sizes) => byte_progress_bar(sizes.values().sum(), args.quiet),
+            None => progress_bar(matched_files.len(), args.quiet),
+        };
+        let progress = |files: &[PathBuf]| {
+            bar.inc(match &sizes {
+                Some(sizes) => files.iter().filter_map(|file| sizes.get(file)).sum(),
+                None => files.len() as u64,
+            })
+        };
+        let counts = if let Some(range) = args.byte_range {
+            vec![count_byte_range(&counter, matched_files, range)]
+        } else if args.concat {
+            let label = PathBuf::from(CONCAT_LABEL);
+            vec![counter.count_concatenated(matched_files, &args.concat_separator, label)]
+        } else if args.count_paths {
+            // Printed paths are counted, they are made relative again with other counts
+            let paths: Vec<PathBuf> = matched_files.iter().cloned().map(relative).collect();
+            let mut counts = counter.count_path_names(&paths);
+            for (count, file) in counts.iter_mut().zip(matched_files) {
+                count.path = file.clone();
+            }
+            counts
+        } else if args.per_line {
+            counter.count_lines(matched_files)
+        } else if let (Some(cache_file), Some(identity)) = (&args.cache_file, cache_identity) {
+            let mut cache = CountCache::load(PathBuf::from(cache_file), identity);
+            let counts = counter.count_paths_with_cache(matched_files, &mut cache, |processed| {
+                bar.inc(processed as u64)
+            });
+            if let Err(e) = cache.save()
+                && !args.quiet
+            {
+                eprintln!("Cannot save count cache {cache_file}: {e}");
+            }
+            counts
+        } else if writer.streams_records() && !args.tree {
+            // Records are written while other chunks are still counted
+            streamed = true;
+            let stream = Mutex::new((&mut writer, &mut out));
+            counter.count_paths_with_callback(matched_files, |file_chunk, counts| {
+                progress(file_chunk);
+                let mut stream = stream.lock().expect("Output lock poisoned");
+                let (writer, out) = &mut *stream;
+                counts
+                    .iter()
+                    .try_for_each(|count| {
+                        let count = FileCount {
+                            path: relative(count.path.clone()),
+                            ..count.clone()
+                        };
+                        writer.write_streamed(count, out)
+                    })
+                    .and_then(|_| out.flush())
+                    .expect("Error while writing output");
+            })
+        } else {
+            counter.count_paths_with_callback(matched_files, |file_chunk, _| progress(file_chunk))
+        };
+        bar.finish_and_clear();
+        counts
+    } else if args.stdin_lines {
+        count_stdin_lines(&counter, &args.stdin_name)
+    } else {
+        // Pipe
+        let mut data = read_pipe();
+        if args.normalize_newlines {
+            data = normalize_newlines(data);
+        }
+        vec![counter.count_text(PathBuf::from(&args.stdin_name), &data)]
+    };
+    let encoding_time = encoding_start.elapsed();
+    let counts: Vec<FileCount> = counts
+        .into_iter()
+        .map(|count| FileCount {
+            path: relative(count.path),
+            ..count
+        })
+        .collect();
+    let skipped_files = matched_files.is_some().then(|| {
+        let mut skipped_files = counter.skipped_files();
+        for skipped in &mut skipped_files {
+            skipped.path = relative(std::mem::take(&mut skipped.path));
+        }
+        skipped_files
+    });
+    let mut writer = writer.with_errors(skipped_files);
+    if !streamed {
+        for count in &counts {
+            writer.add(count.clone());
+        }
+    }
+    let total = writer.total();
+    let summary = writer.summary();
+    let written = if args.tree {
+        write!(out, "{}", tree(&counts, args.verbose))
+    } else {
+        writer.finish(&mut out)
+    };
+    written
+        .and_then(|_| out.flush())
+        .expect("Error while writing output");
+    if args.summary {
+        eprintln!("{summary}");
+    }
+    if args.histogram {
+        eprint!("{}", histogram(&counts));
+    }
+    if args.count_empty {
+        print_empty_files(&counts);
+    }
+    let missing_variables = counter.missing_variables();
+    if !missing_variables.is_empty() && !args.quiet {
+        let placeholders: Vec<String> = missing_variables
+            .iter()
+            .map(|name| format!("{{{{{name}}}}}"))
+            .collect();
+        eprintln!(
+            "Placeholders without --vars value were counted as is: {}",
+            placeholders.join(", ")
+        );
+    }
+    if args.timing {
+        print_timing(&[
+            ("discovery", discovery_time),
+            ("tokenizer", tokenizer_time),
+            ("encoding", encoding_time),
+            ("total", start.elapsed()),
+        ]);
+    }
+    if args.watch {
+        run_watch(&args, &counter, matched_files.unwrap_or_default(), &mut out);
+        return;
+    }
+    if counter.is_interrupted() {
+        eprintln!("Interrupted, counts are partial");
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    if let Some(threshold) = args.threshold
+        && exceeds_threshold(&counts, total, threshold, args.verbose)
+    {
+        std::process::exit(1);
+    }
+    if counter.read_errors() > 0 {
+        eprintln!(
+            "{} files could not be read, counts are partial",
+            counter.read_errors()
+        );
+        std::process::exit(READ_ERROR_EXIT_CODE);
+    }
+    if args.fail_on_binary || args.strict_encoding {
+        let binary_files: Vec<PathBuf> = counter
+            .skipped_files()
+            .into_iter()
+            .filter(|skipped| skipped.reason == BINARY_FILE_REASON)
+            .map(|skipped| skipped.path)
+            .collect();
+        if !binary_files.is_empty() {
+            if args.strict_encoding {
+                eprintln!("Binary or non UTF-8 files found:");
+            } else {
+                eprintln!("Binary files found:");
+            }
+            for file in binary_files {
+                eprintln!("{}", file.display());
+            }
+            std::process::exit(BINARY_FILE_EXIT_CODE);
+        }
     }
 }
@@ -0,0 +1,1668 @@
+use crate::backend::TokenOffset;
+use crate::counter::{FileCount, SkippedFile, path_to_string};
+use clap::ValueEnum;
+use regex::Regex;
+use serde_json::{Map, Value, json};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// ANSI escape of red foreground of highlighted records
+const RED: &str = "\x1b[31m";
+/// ANSI escape of dimmed counts
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Format of the printed results
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Plain `file_name length` lines
+    #[default]
+    Text,
+    /// Json array of `{"path", "tokens"}` objects or `{"total"}` object
+    Json,
+    /// Comma separated values with header
+    Csv,
+    /// Json object per line for each file followed by `{"total"}` object, records are
+    /// written as soon as files are counted
+    Ndjson,
+}
+
+/// Order of printed records
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Order of matched files, by depth and then lexically by default
+    #[default]
+    Path,
+    /// Ascending token count
+    TokensAsc,
+    /// Descending token count
+    TokensDesc,
+}
+
+/// Label of group of files whose path does not match regex of `GroupBy::Regex`
+pub const UNMATCHED_GROUP: &str = "(unmatched)";
+
+/// Aggregation of per-file counts into groups
+#[derive(Clone, Debug)]
+pub enum GroupBy {
+    /// Group files by parent directory
+    Directory,
+    /// Group files by extension, groups are sorted by descending token count
+    Extension,
+    /// Group files by first capture group of regex matched against path, or by whole match
+    /// if regex has no groups. Groups are sorted by descending token count
+    Regex(Regex),
+}
+
+impl GroupBy {
+    /// Name of the group column
+    fn label(&self) -> &'static str {
+        match self {
+            GroupBy::Directory => "directory",
+            GroupBy::Extension => "extension",
+            GroupBy::Regex(_) => "group",
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        match self {
+            GroupBy::Directory => match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => {
+                    parent.to_str().unwrap_or("").to_string()
+                }
+                _ => ".".to_string(),
+            },
+            GroupBy::Extension => match path.extension() {
+                Some(extension) => format!(".{}", extension.to_str().unwrap_or("")),
+                None => "(none)".to_string(),
+            },
+            GroupBy::Regex(regex) => regex
+                .captures(&path.to_string_lossy())
+                .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .map_or(UNMATCHED_GROUP.to_string(), |group| {
+                    group.as_str().to_string()
+                }),
+        }
+    }
+
+    /// Sums counts of records with the same key, groups are sorted by key
+    /// or by descending tokens for extensions and regex groups
+    fn aggregate(&self, records: Vec<FileCount>) -> Vec<FileCount> {
+        let mut groups: BTreeMap<String, FileCount> = BTreeMap::new();
+        for count in records {
+            let key = self.key(&count.path);
+            let group = groups.entry(key.clone()).or_insert_with(|| FileCount {
+                path: PathBuf::from(key),
+                ..FileCount::default()
+            });
+            add_counts(group, &count);
+        }
+        let mut groups: Vec<FileCount> = groups.into_values().collect();
+        if matches!(self, GroupBy::Extension | GroupBy::Regex(_)) {
+            groups.sort_by_key(|group| Reverse(group.tokens));
+        }
+        groups
+    }
+}
+
+/// Numeric column printed for each record
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Column {
+    Tokens,
+    /// Token count of named backend, index 0 is the main backend
+    Backend(usize, String),
+    /// Tokens beyond context window
+    Overflow,
+    /// Chunks of sliding window chunking
+    Chunks,
+    Words,
+    Chars,
+    /// Percentage of main token count in budget of N tokens
+    Budget(usize),
+}
+
+impl Column {
+    fn name(&self) -> &str {
+        match self {
+            Column::Tokens => "tokens",
+            Column::Backend(_, name) => name,
+            Column::Overflow => "overflow",
+            Column::Chunks => "chunks",
+            Column::Words => "words",
+            Column::Chars => "chars",
+            Column::Budget(_) => "budget_percent",
+        }
+    }
+
+    /// Name of the column in the total row
+    fn total_name(&self) -> &str {
+        match self {
+            Column::Tokens => "total",
+            _ => self.name(),
+        }
+    }
+
+    fn value(&self, count: &FileCount) -> Value {
+        match self {
+            Column::Tokens | Column::Backend(0, _) => json!(count.tokens),
+            Column::Backend(index, _) => {
+                json!(count.other_tokens.get(index - 1).copied().unwrap_or(0))
+            }
+            Column::Overflow => json!(count.overflow),
+            Column::Chunks => json!(count.chunks),
+            Column::Words => json!(count.words),
+            Column::Chars => json!(count.chars),
+            Column::Budget(budget) => json!((percent(count.tokens, *budget) * 10.0).round() / 10.0),
+        }
+    }
+
+    /// Main token count is printed as share of budget in text format
+    fn is_main(&self) -> bool {
+        matches!(self, Column::Tokens | Column::Backend(0, _))
+    }
+}
+
+/// Error of parsing output template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// Placeholder name is not one of supported names
+    UnknownPlaceholder(String),
+    /// Placeholder is opened with `{` but never closed
+    UnclosedPlaceholder,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(name) => write!(
+                f,
+                "Unknown placeholder {{{name}}}, expected one of {{path}}, {{tokens}}, \
+                 {{words}}, {{chars}}, {{overflow}}, {{chunks}}"
+            ),
+            TemplateError::UnclosedPlaceholder => write!(f, "Placeholder is not closed with }}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Path,
+    Value(Column),
+}
+
+/// Format of text record line with placeholders `{path}`, `{tokens}`, `{words}`, `{chars}`,
+/// `{overflow}` and `{chunks}`. `\t`, `\n` and `\\` are unescaped, `{{` and `}}` print braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('\\') => literal.push('\\'),
+                    Some(other) => literal.extend(['\\', other]),
+                    None => literal.push('\\'),
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::UnclosedPlaceholder),
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "path" => TemplatePart::Path,
+                        "tokens" => TemplatePart::Value(Column::Tokens),
+                        "words" => TemplatePart::Value(Column::Words),
+                        "chars" => TemplatePart::Value(Column::Chars),
+                        "overflow" => TemplatePart::Value(Column::Overflow),
+                        "chunks" => TemplatePart::Value(Column::Chunks),
+                        _ => return Err(TemplateError::UnknownPlaceholder(name)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    fn render(&self, path: &str, count: &FileCount) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.clone(),
+                TemplatePart::Path => path.to_string(),
+                TemplatePart::Value(column) => column.value(count).to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Collects per-file token counts and writes them in the requested format.
+pub struct OutputWriter {
+    format: OutputFormat,
+    verbose: bool,
+    stats: bool,
+    top: Option<usize>,
+    min_tokens: Option<usize>,
+    group_by: Option<GroupBy>,
+    sort: SortOrder,
+    tokenizer_names: Vec<String>,
+    unit: Option<String>,
+    context_window: Option<usize>,
+    chunking: Option<(usize, usize)>,
+    budget: Option<usize>,
+    template: Option<Template>,
+    print0: bool,
+    color: bool,
+    highlight: Option<usize>,
+    separator: String,
+    errors: Option<Vec<SkippedFile>>,
+    per_line: bool,
+    records: Vec<FileCount>,
+    totals: FileCount,
+    files: usize,
+}
+
+impl OutputWriter {
+    pub fn new(format: OutputFormat, verbose: bool) -> Self {
+        OutputWriter {
+            format,
+            verbose,
+            stats: false,
+            top: None,
+            min_tokens: None,
+            group_by: None,
+            sort: SortOrder::Path,
+            tokenizer_names: Vec::new(),
+            unit: None,
+            context_window: None,
+            chunking: None,
+            budget: None,
+            template: None,
+            print0: false,
+            color: false,
+            highlight: None,
+            separator: " ".to_string(),
+            errors: None,
+            per_line: false,
+            records: Vec::new(),
+            totals: FileCount::default(),
+            files: 0,
+        }
+    }
+
+    /// Print word and char counts next to token counts
+    pub fn with_stats(mut self, stats: bool) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Print only `top` files with the largest token count followed by total
+    pub fn with_top(mut self, top: Option<usize>) -> Self {
+        self.top = top;
+        self
+    }
+
+    /// Hide records with fewer tokens, totals still include them
+    pub fn with_min_tokens(mut self, min_tokens: Option<usize>) -> Self {
+        self.min_tokens = min_tokens;
+        self
+    }
+
+    /// Print subtotals of groups instead of per-file counts
+    pub fn with_group_by(mut self, group_by: Option<GroupBy>) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    /// Order of printed records, sorting is stable
+    pub fn with_sort(mut self, sort: SortOrder) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Names of tokenizers, with more than one name a column per tokenizer is printed
+    pub fn with_tokenizer_names(mut self, tokenizer_names: Vec<String>) -> Self {
+        self.tokenizer_names = tokenizer_names;
+        self
+    }
+
+    /// Name of counted unit used as column name instead of `tokens`, e.g. `lines`
+    pub fn with_unit(mut self, unit: Option<String>) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Print number of tokens beyond model context window of each file and their total
+    pub fn with_context_window(mut self, context_window: Option<usize>) -> Self {
+        self.context_window = context_window;
+        self
+    }
+
+    /// Print number of chunks of `window` tokens overlapping by `overlap` tokens of each file
+    /// and their total, `overlap` must be less than `window`
+    pub fn with_chunking(mut self, window: Option<usize>, overlap: usize) -> Self {
+        self.chunking = window.map(|window| (window, overlap));
+        self
+    }
+
+    /// Print token counts as share of budget of N tokens, e.g. `1234/8000 (15.4%)`
+    pub fn with_budget(mut self, budget: Option<usize>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Format of record lines in text format, total line uses `total` as path
+    pub fn with_template(mut self, template: Option<Template>) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Terminate each field of text records with NUL byte instead of space and newline,
+    /// like `find -print0`
+    pub fn with_print0(mut self, print0: bool) -> Self {
+        self.print0 = print0;
+        self
+    }
+
+    /// Print counts of text records dimmed and highlighted paths in red with ANSI escapes.
+    /// Ignored with `print0`.
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Paths of text records with more tokens are printed in red with `color`
+    pub fn with_highlight(mut self, highlight: Option<usize>) -> Self {
+        self.highlight = highlight;
+        self
+    }
+
+    /// Separator of path and count fields of text records, space by default.
+    /// Ignored with `print0`.
+    pub fn with_separator(mut self, separator: String) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Skipped files listed in `errors` array of JSON output, other formats ignore them
+    pub fn with_errors(mut self, errors: Option<Vec<SkippedFile>>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Records are counts of lines, summary reports statistics of tokens per line
+    pub fn with_per_line(mut self, per_line: bool) -> Self {
+        self.per_line = per_line;
+        self
+    }
+
+    pub fn add(&mut self, count: FileCount) {
+        let count = self.accumulate(count);
+        if self.prints_records() {
+            self.records.push(count);
+        }
+    }
+
+    /// Adds count and writes it at once as a line of `Ndjson` output, so records are
+    /// consumed while other files are counted. Streamed records are not written by `finish`.
+    pub fn write_streamed(&mut self, count: FileCount, out: &mut impl Write) -> io::Result<()> {
+        let count = self.accumulate(count);
+        if self
+            .min_tokens
+            .is_none_or(|min_tokens| count.tokens >= min_tokens)
+        {
+            writeln!(out, "{}", self.json_record(&count))?;
+        }
+        Ok(())
+    }
+
+    /// Records of `Ndjson` output can be written with `write_streamed` unless they are
+    /// sorted, grouped or limited to top files
+    pub fn streams_records(&self) -> bool {
+        self.format == OutputFormat::Ndjson
+            && self.top.is_none()
+            && self.group_by.is_none()
+            && self.sort == SortOrder::Path
+    }
+
+    /// Adds count to totals, returns it with overflow of context window
+    fn accumulate(&mut self, mut count: FileCount) -> FileCount {
+        if let Some(context_window) = self.context_window {
+            count.overflow = count.tokens.saturating_sub(context_window);
+        }
+        if let Some((window, overlap)) = self.chunking {
+            count.chunks = chunk_count(count.tokens, window, overlap);
+        }
+        add_counts(&mut self.totals, &count);
+        self.files += 1;
+        count
+    }
+
+    pub fn total(&self) -> u64 {
+        self.totals.tokens as u64
+    }
+
+    /// One line summary `files=N tokens=T avg=A` with mean tokens per file. With `per_line`
+    /// it is `lines=N tokens=T min=M max=X avg=A` with statistics of tokens per line.
+    pub fn summary(&self) -> String {
+        let avg = if self.files == 0 {
+            0.0
+        } else {
+            self.total() as f64 / self.files as f64
+        };
+        if self.per_line {
+            let tokens = self.records.iter().map(|count| count.tokens);
+            return format!(
+                "lines={} tokens={} min={} max={} avg={avg:.1}",
+                self.files,
+                self.total(),
+                tokens.clone().min().unwrap_or(0),
+                tokens.max().unwrap_or(0)
+            );
+        }
+        format!("files={} tokens={} avg={avg:.1}", self.files, self.total())
+    }
+
+    pub fn finish(mut self, out: &mut impl Write) -> io::Result<()> {
+        if let Some(group_by) = &self.group_by {
+            self.records = group_by.aggregate(std::mem::take(&mut self.records));
+        }
+        if let Some(min_tokens) = self.min_tokens {
+            self.records.retain(|count| count.tokens >= min_tokens);
+        }
+        if let Some(top) = self.top {
+            // Stable sort keeps path order for files with equal count
+            self.records.sort_by_key(|count| Reverse(count.tokens));
+            self.records.truncate(top);
+        }
+        match self.sort {
+            SortOrder::Path => {}
+            SortOrder::TokensAsc => self.records.sort_by_key(|count| count.tokens),
+            SortOrder::TokensDesc => self.records.sort_by_key(|count| Reverse(count.tokens)),
+        }
+        match self.format {
+            OutputFormat::Text => self.write_text(out),
+            OutputFormat::Json => self.write_json(out),
+            OutputFormat::Csv => self.write_csv(out),
+            OutputFormat::Ndjson => self.write_ndjson(out),
+        }
+    }
+
+    fn prints_records(&self) -> bool {
+        self.verbose
+            || self.top.is_some()
+            || self.group_by.is_some()
+            || self.format == OutputFormat::Ndjson
+    }
+
+    /// Summary row is printed after records
+    fn prints_summary(&self) -> bool {
+        self.stats
+            || self.top.is_some()
+            || self.group_by.is_some()
+            || self.context_window.is_some()
+            || self.chunking.is_some()
+            || self.budget.is_some()
+            || self.min_tokens.is_some()
+    }
+
+    /// Name of the first column of records
+    fn label(&self) -> &'static str {
+        self.group_by
+            .as_ref()
+            .map(|group_by| group_by.label())
+            .unwrap_or("path")
+    }
+
+    fn columns(&self) -> Vec<Column> {
+        let mut columns = if self.tokenizer_names.len() > 1 {
+            self.tokenizer_names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| Column::Backend(index, name.clone()))
+                .collect()
+        } else if let Some(unit) = &self.unit {
+            vec![Column::Backend(0, unit.clone())]
+        } else {
+            vec![Column::Tokens]
+        };
+        if self.context_window.is_some() {
+            columns.push(Column::Overflow);
+        }
+        if self.chunking.is_some() {
+            columns.push(Column::Chunks);
+        }
+        if let Some(budget) = self.budget
+            && self.format != OutputFormat::Text
+        {
+            columns.push(Column::Budget(budget));
+        }
+        if self.stats {
+            columns.extend([Column::Words, Column::Chars]);
+        }
+        columns
+    }
+
+    fn values(&self, count: &FileCount, separator: &str) -> String {
+        self.columns()
+            .iter()
+            .map(|column| match self.budget {
+                Some(budget) if column.is_main() && self.format == OutputFormat::Text => {
+                    let tokens = count.tokens;
+                    format!("{tokens}/{budget} ({:.1}%)", percent(tokens, budget))
+                }
+                _ => column.value(count).to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(separator)
+    }
+
+    fn json_values(&self, count: &FileCount) -> Map<String, Value> {
+        self.columns()
+            .iter()
+            .map(|column| (column.name().to_string(), column.value(count)))
+            .collect()
+    }
+
+    /// Json object with path or group name and values of record
+    fn json_record(&self, count: &FileCount) -> Value {
+        let mut values = self.json_values(count);
+        values.insert(self.label().to_string(), json!(count.get_path_string()));
+        Value::Object(values)
+    }
+
+    /// Json object with totals and skipped files if they are set
+    fn json_total(&self) -> Map<String, Value> {
+        let mut total: Map<String, Value> = self
+            .columns()
+            .iter()
+            .map(|column| (column.total_name().to_string(), column.value(&self.totals)))
+            .collect();
+        if let Some(errors) = &self.errors {
+            let errors: Value = errors
+                .iter()
+                .map(|error| json!({"path": path_to_string(&error.path), "reason": error.reason}))
+                .collect();
+            total.insert("errors".to_string(), errors);
+        }
+        total
+    }
+
+    fn text_line(&self, path: &str, count: &FileCount, separator: &str) -> String {
+        match &self.template {
+            Some(template) => template.render(path, count),
+            None if self.is_colored() => {
+                format!(
+                    "{path}{separator}{DIM}{}{RESET}",
+                    self.values(count, separator)
+                )
+            }
+            None => format!("{path}{separator}{}", self.values(count, separator)),
+        }
+    }
+
+    fn is_colored(&self) -> bool {
+        self.color && !self.print0
+    }
+
+    /// Path of record, in red if record has more tokens than highlight threshold
+    fn text_path(&self, count: &FileCount) -> String {
+        let path = count.get_path_string();
+        match self.highlight {
+            Some(highlight) if self.is_colored() && count.tokens > highlight => {
+                format!("{RED}{path}{RESET}")
+            }
+            _ => path,
+        }
+    }
+
+    fn write_text(&self, out: &mut impl Write) -> io::Result<()> {
+        let (separator, terminator) = if self.print0 {
+            ("\0", "\0")
+        } else {
+            (self.separator.as_str(), "\n")
+        };
+        if self.prints_records() {
+            for count in &self.records {
+                let line = self.text_line(&self.text_path(count), count, separator);
+                write!(out, "{line}{terminator}")?;
+            }
+            if self.prints_summary() {
+                let line = self.text_line("total", &self.totals, separator);
+                write!(out, "{line}{terminator}")?;
+            }
+        } else {
+            write!(out, "{}{terminator}", self.values(&self.totals, separator))?;
+        }
+        Ok(())
+    }
+
+    fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut total = self.json_total();
+        let value = if self.prints_records() {
+            let records: Value = self
+                .records
+                .iter()
+                .map(|count| self.json_record(count))
+                .collect();
+            if self.top.is_some()
+                || self.group_by.is_some()
+                || self.context_window.is_some()
+                || self.chunking.is_some()
+                || self.budget.is_some()
+                || self.min_tokens.is_some()
+                || self.errors.is_some()
+            {
+                let key = if self.group_by.is_some() {
+                    "groups"
+                } else {
+                    "files"
+                };
+                total.insert(key.to_string(), records);
+                Value::Object(total)
+            } else {
+                records
+            }
+        } else {
+            Value::Object(total)
+        };
+        writeln!(out, "{value}")
+    }
+
+    fn write_ndjson(&self, out: &mut impl Write) -> io::Result<()> {
+        for count in &self.records {
+            writeln!(out, "{}", self.json_record(count))?;
+        }
+        writeln!(out, "{}", Value::Object(self.json_total()))
+    }
+
+    fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.prints_records() {
+            let columns = self.columns();
+            let header: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+            writeln!(out, "{},{}", self.label(), header.join(","))?;
+            for count in &self.records {
+                let path = csv_escape(&count.get_path_string());
+                writeln!(out, "{path},{}", self.values(count, ","))?;
+            }
+            if self.prints_summary() {
+                writeln!(out, "total,{}", self.values(&self.totals, ","))?;
+            }
+        } else {
+            let columns = self.columns();
+            let header: Vec<&str> = columns.iter().map(|column| column.total_name()).collect();
+            writeln!(out, "{}", header.join(","))?;
+            writeln!(out, "{}", self.values(&self.totals, ","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of chunks of `window` tokens, each starting `overlap` tokens before the end of the
+/// previous one, needed to cover `tokens`. Empty text has no chunks.
+pub fn chunk_count(tokens: usize, window: usize, overlap: usize) -> usize {
+    if tokens == 0 {
+        return 0;
+    }
+    1 + tokens.saturating_sub(window).div_ceil(window - overlap)
+}
+
+/// Percentage of `tokens` in `budget`, may exceed 100
+fn percent(tokens: usize, budget: usize) -> f64 {
+    tokens as f64 * 100.0 / budget as f64
+}
+
+/// Width of the longest bar of histogram
+const HISTOGRAM_WIDTH: usize = 50;
+
+/// ASCII bar chart of number of files per token count. Buckets are powers of two,
+/// e.g. `64-127`, empty buckets between the smallest and the largest count are kept.
+pub fn histogram(counts: &[FileCount]) -> String {
+    let mut buckets: Vec<usize> = Vec::new();
+    for count in counts {
+        let bucket = count
+            .tokens
+            .checked_ilog2()
+            .map_or(0, |log| log as usize + 1);
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+    let Some(first) = buckets.iter().position(|files| *files > 0) else {
+        return String::new();
+    };
+    let max_files = buckets.iter().copied().max().unwrap_or(1);
+    let label = |bucket: usize| match bucket {
+        0 => "0".to_string(),
+        _ => format!(
+            "{}-{}",
+            1usize << (bucket - 1),
+            usize::MAX >> (usize::BITS as usize - bucket)
+        ),
+    };
+    let width = (first..buckets.len())
+        .map(|bucket| label(bucket).len())
+        .max()
+        .unwrap_or(0);
+    (first..buckets.len())
+        .map(|bucket| {
+            let files = buckets[bucket];
+            let bar = "#".repeat((files * HISTOGRAM_WIDTH).div_ceil(max_files));
+            format!("{:>width$} | {bar} {files}\n", label(bucket))
+        })
+        .collect()
+}
+
+/// Directory or file of `tree` report with recursive token total
+#[derive(Default)]
+struct TreeNode {
+    tokens: usize,
+    is_file: bool,
+    children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn write(&self, name: &str, depth: usize, files: bool, out: &mut String) {
+        let suffix = if self.is_file { "" } else { "/" };
+        let name = name.trim_end_matches('/');
+        out.push_str(&format!(
+            "{}{} {name}{suffix}\n",
+            "  ".repeat(depth),
+            self.tokens
+        ));
+        let mut children: Vec<(&String, &TreeNode)> = self
+            .children
+            .iter()
+            .filter(|(_, child)| files || !child.is_file)
+            .collect();
+        // Stable sort keeps name order for equal totals
+        children.sort_by_key(|(_, child)| Reverse(child.tokens));
+        for (name, child) in children {
+            child.write(name, depth + 1, files, out);
+        }
+    }
+}
+
+/// Indented tree of directories with recursive token totals, like `du` for tokens. Children
+/// are sorted by descending total, directories with a single subdirectory and no files at the
+/// top are merged into one node. Files are listed only if `files` is set.
+pub fn tree(counts: &[FileCount], files: bool) -> String {
+    let mut root = TreeNode::default();
+    for count in counts {
+        root.tokens += count.tokens;
+        let mut node = &mut root;
+        for component in count.path.components() {
+            let name = component.as_os_str().to_string_lossy().into_owned();
+            node = node.children.entry(name).or_default();
+            node.tokens += count.tokens;
+        }
+        node.is_file = true;
+    }
+    let mut name = PathBuf::new();
+    let mut top = &root;
+    while top.children.len() == 1 {
+        let (child_name, child) = top.children.iter().next().expect("Single child");
+        if child.is_file {
+            break;
+        }
+        name.push(child_name);
+        top = child;
+    }
+    let name = if name.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        name.to_string_lossy().into_owned()
+    };
+    let mut out = String::new();
+    top.write(&name, 0, files, &mut out);
+    out
+}
+
+/// Token counts of a file matched by relative path in two compared trees, `None` if the file
+/// is absent in the tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileDelta {
+    pub path: PathBuf,
+    pub before: Option<usize>,
+    pub after: Option<usize>,
+}
+
+impl FileDelta {
+    /// Change of token count, counts of absent files are 0
+    pub fn delta(&self) -> i64 {
+        self.after.unwrap_or(0) as i64 - self.before.unwrap_or(0) as i64
+    }
+
+    fn kind(&self) -> &'static str {
+        match (self.before, self.after) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "changed",
+        }
+    }
+}
+
+/// Matches counts of two trees by path, files with the same count in both trees are dropped.
+/// Deltas are sorted by path.
+pub fn compare_counts(before: Vec<FileCount>, after: Vec<FileCount>) -> Vec<FileDelta> {
+    let mut deltas: BTreeMap<PathBuf, FileDelta> = BTreeMap::new();
+    for (count, is_after) in before
+        .into_iter()
+        .map(|count| (count, false))
+        .chain(after.into_iter().map(|count| (count, true)))
+    {
+        let delta = deltas
+            .entry(count.path.clone())
+            .or_insert_with(|| FileDelta {
+                path: count.path,
+                before: None,
+                after: None,
+            });
+        if is_after {
+            delta.after = Some(count.tokens);
+        } else {
+            delta.before = Some(count.tokens);
+        }
+    }
+    deltas
+        .into_values()
+        .filter(|delta| delta.before != delta.after)
+        .collect()
+}
+
+/// Report of `compare_counts` with number of files and tokens added, removed and changed and
+/// totals of both trees. Changes of each file are listed only if `files` is set.
+pub fn comparison(
+    deltas: &[FileDelta],
+    before_total: usize,
+    after_total: usize,
+    files: bool,
+    format: OutputFormat,
+) -> String {
+    let kinds = ["added", "removed", "changed"];
+    let summary: Vec<(&str, usize, i64)> = kinds
+        .iter()
+        .map(|kind| {
+            let deltas = deltas.iter().filter(|delta| delta.kind() == *kind);
+            (
+                *kind,
+                deltas.clone().count(),
+                deltas.map(FileDelta::delta).sum(),
+            )
+        })
+        .collect();
+    let total_delta = after_total as i64 - before_total as i64;
+    let delta_json = |delta: &FileDelta| {
+        json!({
+            "path": path_to_string(&delta.path),
+            "before": delta.before,
+            "after": delta.after,
+            "delta": delta.delta(),
+        })
+    };
+    let mut total = json!({"before": before_total, "after": after_total, "delta": total_delta});
+    for (kind, files, tokens) in &summary {
+        total[kind] = json!({"files": files, "tokens": tokens});
+    }
+    match format {
+        OutputFormat::Text => {
+            let mut out = String::new();
+            if files {
+                for delta in deltas {
+                    let path = path_to_string(&delta.path);
+                    out += &match (delta.before, delta.after) {
+                        (None, Some(after)) => format!("+ {path} {after}\n"),
+                        (Some(before), None) => format!("- {path} {before}\n"),
+                        (before, after) => format!(
+                            "~ {path} {} -> {} ({:+})\n",
+                            before.unwrap_or(0),
+                            after.unwrap_or(0),
+                            delta.delta()
+                        ),
+                    };
+                }
+            }
+            for (kind, files, tokens) in &summary {
+                out += &format!("{kind} {files} files {tokens:+} tokens\n");
+            }
+            out + &format!("total {before_total} -> {after_total} ({total_delta:+})\n")
+        }
+        OutputFormat::Json => {
+            if files {
+                total["files"] = deltas.iter().map(delta_json).collect();
+            }
+            format!("{total}\n")
+        }
+        OutputFormat::Ndjson => {
+            let mut out = String::new();
+            if files {
+                for delta in deltas {
+                    out += &format!("{}\n", delta_json(delta));
+                }
+            }
+            out + &format!("{total}\n")
+        }
+        OutputFormat::Csv => {
+            let mut out = "path,before,after,delta\n".to_string();
+            if files {
+                for delta in deltas {
+                    let count = |count: Option<usize>| count.map(|c| c.to_string());
+                    out += &format!(
+                        "{},{},{},{}\n",
+                        csv_escape(&path_to_string(&delta.path)),
+                        count(delta.before).unwrap_or_default(),
+                        count(delta.after).unwrap_or_default(),
+                        delta.delta()
+                    );
+                }
+            }
+            out + &format!("total,{before_total},{after_total},{total_delta}\n")
+        }
+    }
+}
+
+/// Table of tokens with byte offsets: tab separated values for text format with tabs and
+/// newlines of tokens escaped, json array of objects, json object per line or csv
+pub fn offsets(tokens: &[TokenOffset], format: OutputFormat) -> String {
+    let token_json = |token: &TokenOffset| {
+        let TokenOffset {
+            id,
+            token,
+            start,
+            end,
+        } = token;
+        json!({"id": id, "token": token, "start": start, "end": end})
+    };
+    match format {
+        OutputFormat::Json => {
+            let tokens: Value = tokens.iter().map(token_json).collect();
+            format!("{tokens}\n")
+        }
+        OutputFormat::Ndjson => tokens
+            .iter()
+            .map(|token| format!("{}\n", token_json(token)))
+            .collect(),
+        OutputFormat::Text | OutputFormat::Csv => {
+            let (separator, escape): (&str, fn(&str) -> String) = if format == OutputFormat::Csv {
+                (",", csv_escape)
+            } else {
+                ("\t", tsv_escape)
+            };
+            let mut out = ["id", "token", "start", "end"].join(separator) + "\n";
+            for token in tokens {
+                let fields = [
+                    token.id.to_string(),
+                    escape(&token.token),
+                    token.start.to_string(),
+                    token.end.to_string(),
+                ];
+                out += &(fields.join(separator) + "\n");
+            }
+            out
+        }
+    }
+}
+
+/// Corpus size and wall time of each counting run of `--benchmark`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Benchmark {
+    pub tokenizer: String,
+    /// Number of files or texts of corpus
+    pub files: usize,
+    /// Bytes of corpus
+    pub bytes: u64,
+    /// Tokens of corpus counted in one run
+    pub tokens: usize,
+    pub runs: Vec<Duration>,
+}
+
+/// Report of throughput of all runs of `benchmark`, with minimum and mean time of a run
+pub fn benchmark(benchmark: &Benchmark, format: OutputFormat) -> String {
+    let runs = benchmark.runs.len();
+    let total: Duration = benchmark.runs.iter().sum();
+    let min = benchmark.runs.iter().min().copied().unwrap_or_default();
+    let mean = total.checked_div(runs as u32).unwrap_or_default();
+    let per_second = |amount: f64| {
+        if total.is_zero() {
+            0.0
+        } else {
+            amount * runs as f64 / total.as_secs_f64()
+        }
+    };
+    let tokens_per_second = per_second(benchmark.tokens as f64);
+    let files_per_second = per_second(benchmark.files as f64);
+    let bytes_per_second = per_second(benchmark.bytes as f64);
+    let report = json!({
+        "tokenizer": benchmark.tokenizer,
+        "runs": runs,
+        "files": benchmark.files,
+        "bytes": benchmark.bytes,
+        "tokens": benchmark.tokens,
+        "total_seconds": total.as_secs_f64(),
+        "min_seconds": min.as_secs_f64(),
+        "mean_seconds": mean.as_secs_f64(),
+        "tokens_per_second": tokens_per_second.round(),
+        "files_per_second": files_per_second.round(),
+        "bytes_per_second": bytes_per_second.round(),
+    });
+    match format {
+        OutputFormat::Json | OutputFormat::Ndjson => format!("{report}\n"),
+        OutputFormat::Csv => {
+            let fields = [
+                csv_escape(&benchmark.tokenizer),
+                runs.to_string(),
+                benchmark.files.to_string(),
+                benchmark.bytes.to_string(),
+                benchmark.tokens.to_string(),
+                format!("{:.3}", total.as_secs_f64()),
+                format!("{:.3}", min.as_secs_f64()),
+                format!("{:.3}", mean.as_secs_f64()),
+                format!("{tokens_per_second:.0}"),
+                format!("{files_per_second:.0}"),
+                format!("{bytes_per_second:.0}"),
+            ];
+            "tokenizer,runs,files,bytes,tokens,total_seconds,min_seconds,mean_seconds,\
+             tokens_per_second,files_per_second,bytes_per_second\n"
+                .to_string()
+                + &fields.join(",")
+                + "\n"
+        }
+        OutputFormat::Text => format!(
+            "tokenizer {}\nruns {runs}\nfiles {}\nbytes {}\ntokens {}\n\
+             time {:.3}s total, {:.3}s min, {:.3}s mean\n\
+             tokens/s {tokens_per_second:.0}\nfiles/s {files_per_second:.0}\n\
+             bytes/s {bytes_per_second:.0}\n",
+            benchmark.tokenizer,
+            benchmark.files,
+            benchmark.bytes,
+            benchmark.tokens,
+            total.as_secs_f64(),
+            min.as_secs_f64(),
+            mean.as_secs_f64(),
+        ),
+    }
+}
+
+/// Metadata of loaded tokenizer printed by `--tokenizer-info`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenizerInfo {
+    /// Name printed in output, e.g. model identifier
+    pub name: String,
+    /// How the tokenizer was selected, e.g. option or environment variable
+    pub source: String,
+    pub vocab_size: Option<usize>,
+    pub special_tokens: Vec<String>,
+    pub fingerprint: Option<String>,
+}
+
+/// Report of tokenizers, text format prints `key: value` lines separated by empty line
+/// per tokenizer
+pub fn tokenizer_info(infos: &[TokenizerInfo], format: OutputFormat) -> String {
+    let info_json = |info: &TokenizerInfo| {
+        json!({
+            "name": info.name,
+            "source": info.source,
+            "vocab_size": info.vocab_size,
+            "special_tokens": info.special_tokens,
+            "fingerprint": info.fingerprint,
+        })
+    };
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    match format {
+        OutputFormat::Json => {
+            let infos: Value = infos.iter().map(info_json).collect();
+            format!("{infos}\n")
+        }
+        OutputFormat::Ndjson => infos
+            .iter()
+            .map(|info| format!("{}\n", info_json(info)))
+            .collect(),
+        OutputFormat::Csv => {
+            let mut out = "name,source,vocab_size,special_tokens,fingerprint\n".to_string();
+            for info in infos {
+                let fields = [
+                    csv_escape(&info.name),
+                    csv_escape(&info.source),
+                    info.vocab_size
+                        .map(|size| size.to_string())
+                        .unwrap_or_default(),
+                    csv_escape(&info.special_tokens.join(" ")),
+                    info.fingerprint.clone().unwrap_or_default(),
+                ];
+                out += &(fields.join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Text => infos
+            .iter()
+            .map(|info| {
+                format!(
+                    "name: {}\nsource: {}\nvocab size: {}\nspecial tokens: {}\nfingerprint: {}\n",
+                    info.name,
+                    info.source,
+                    optional(info.vocab_size.map(|size| size.to_string())),
+                    optional(
+                        (!info.special_tokens.is_empty()).then(|| info.special_tokens.join(" "))
+                    ),
+                    optional(info.fingerprint.clone()),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Adds counts of `count` to `total`, path is kept
+fn add_counts(total: &mut FileCount, count: &FileCount) {
+    total.tokens += count.tokens;
+    if total.other_tokens.len() < count.other_tokens.len() {
+        total.other_tokens.resize(count.other_tokens.len(), 0);
+    }
+    for (total_tokens, tokens) in total.other_tokens.iter_mut().zip(&count.other_tokens) {
+        *total_tokens += tokens;
+    }
+    total.overflow += count.overflow;
+    total.chunks += count.chunks;
+    total.words += count.words;
+    total.chars += count.chars;
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn tsv_escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn render(writer: OutputWriter) -> String {
+        let mut buffer = Vec::new();
+        writer.finish(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn file_count(path: &str, tokens: usize) -> FileCount {
+        FileCount {
+            path: PathBuf::from(path),
+            tokens,
+            ..FileCount::default()
+        }
+    }
+
+    #[test]
+    fn test_json_verbose_escapes_path() {
+        let mut writer = OutputWriter::new(OutputFormat::Json, true);
+        writer.add(file_count("a\"b\\c.txt", 42));
+        assert_eq!(
+            render(writer),
+            "[{\"path\":\"a\\\"b\\\\c.txt\",\"tokens\":42}]\n"
+        );
+    }
+
+    #[test]
+    fn test_json_total() {
+        let mut writer = OutputWriter::new(OutputFormat::Json, false);
+        writer.add(file_count("a.txt", 2));
+        writer.add(file_count("b.txt", 3));
+        assert_eq!(render(writer), "{\"total\":5}\n");
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let errors = vec![SkippedFile {
+            path: PathBuf::from("b.bin"),
+            reason: crate::counter::BINARY_FILE_REASON.to_string(),
+        }];
+        let mut writer =
+            OutputWriter::new(OutputFormat::Json, true).with_errors(Some(errors.clone()));
+        writer.add(file_count("a.txt", 2));
+        assert_eq!(
+            render(writer),
+            "{\"errors\":[{\"path\":\"b.bin\",\"reason\":\"binary file\"}],\
+             \"files\":[{\"path\":\"a.txt\",\"tokens\":2}],\"total\":2}\n"
+        );
+        let writer = OutputWriter::new(OutputFormat::Json, false).with_errors(Some(errors));
+        assert_eq!(
+            render(writer),
+            "{\"errors\":[{\"path\":\"b.bin\",\"reason\":\"binary file\"}],\"total\":0}\n"
+        );
+    }
+
+    #[test]
+    fn test_ndjson_streamed() {
+        let mut writer = OutputWriter::new(OutputFormat::Ndjson, false).with_min_tokens(Some(2));
+        assert!(writer.streams_records());
+        let mut buffer = Vec::new();
+        writer
+            .write_streamed(file_count("b.txt", 3), &mut buffer)
+            .unwrap();
+        writer
+            .write_streamed(file_count("a.txt", 1), &mut buffer)
+            .unwrap();
+        writer.add(file_count("c.txt", 2));
+        assert_eq!(
+            String::from_utf8(buffer).unwrap() + &render(writer),
+            "{\"path\":\"b.txt\",\"tokens\":3}\n{\"path\":\"c.txt\",\"tokens\":2}\n{\"total\":6}\n"
+        );
+        let writer = OutputWriter::new(OutputFormat::Ndjson, true).with_top(Some(1));
+        assert!(!writer.streams_records());
+    }
+
+    #[test]
+    fn test_csv_verbose_quotes_path() {
+        let mut writer = OutputWriter::new(OutputFormat::Csv, true);
+        writer.add(file_count("a,\"b\".txt", 7));
+        assert_eq!(render(writer), "path,tokens\n\"a,\"\"b\"\".txt\",7\n");
+    }
+
+    #[test]
+    fn test_text_stats() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, true).with_stats(true);
+        writer.add(FileCount::from_text(
+            PathBuf::from("a.txt"),
+            3,
+            "hello world",
+        ));
+        writer.add(FileCount::from_text(PathBuf::from("b.txt"), 1, "hi"));
+        assert_eq!(render(writer), "a.txt 3 2 11\nb.txt 1 1 2\ntotal 4 3 13\n");
+    }
+
+    #[test]
+    fn test_text_top() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, false).with_top(Some(2));
+        writer.add(file_count("a.txt", 1));
+        writer.add(file_count("b.txt", 5));
+        writer.add(file_count("c.txt", 3));
+        assert_eq!(render(writer), "b.txt 5\nc.txt 3\ntotal 9\n");
+    }
+
+    #[test]
+    fn test_text_print0() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, true)
+            .with_stats(true)
+            .with_print0(true);
+        writer.add(FileCount::from_text(PathBuf::from("a b\n.txt"), 2, "a b"));
+        assert_eq!(
+            render(writer),
+            "a b\n.txt\x002\x002\x003\0total\x002\x002\x003\0"
+        );
+        let mut writer = OutputWriter::new(OutputFormat::Text, false).with_print0(true);
+        writer.add(file_count("a.txt", 2));
+        assert_eq!(render(writer), "2\0");
+    }
+
+    #[test]
+    fn test_text_color() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, true)
+            .with_color(true)
+            .with_highlight(Some(3));
+        writer.add(file_count("a.txt", 2));
+        writer.add(file_count("b.txt", 5));
+        assert_eq!(
+            render(writer),
+            "a.txt \x1b[2m2\x1b[0m\n\x1b[31mb.txt\x1b[0m \x1b[2m5\x1b[0m\n"
+        );
+        let mut writer = OutputWriter::new(OutputFormat::Text, true)
+            .with_color(true)
+            .with_highlight(Some(3))
+            .with_print0(true);
+        writer.add(file_count("b.txt", 5));
+        assert_eq!(render(writer), "b.txt\x005\0");
+    }
+
+    #[test]
+    fn test_text_separator() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, true)
+            .with_stats(true)
+            .with_separator("\t".to_string());
+        writer.add(FileCount::from_text(PathBuf::from("a b.txt"), 2, "a b"));
+        assert_eq!(render(writer), "a b.txt\t2\t2\t3\ntotal\t2\t2\t3\n");
+    }
+
+    #[test]
+    fn test_tree() {
+        let counts = vec![
+            file_count("project/README.md", 5),
+            file_count("project/src/main.rs", 10),
+            file_count("project/src/lib.rs", 20),
+            file_count("project/tests/a.rs", 1),
+        ];
+        let expected = ["36 project/", "  30 src/", "  1 tests/"];
+        assert_eq!(tree(&counts, false), expected.join("\n") + "\n");
+        let expected = [
+            "36 project/",
+            "  30 src/",
+            "    20 lib.rs",
+            "    10 main.rs",
+            "  5 README.md",
+            "  1 tests/",
+            "    1 a.rs",
+        ];
+        assert_eq!(tree(&counts, true), expected.join("\n") + "\n");
+        assert_eq!(tree(&[file_count("a.txt", 2)], false), "2 ./\n");
+    }
+
+    #[test]
+    fn test_benchmark() {
+        let report = Benchmark {
+            tokenizer: "gpt2".to_string(),
+            files: 10,
+            bytes: 4000,
+            tokens: 1000,
+            runs: vec![Duration::from_millis(500), Duration::from_millis(1500)],
+        };
+        assert_eq!(
+            benchmark(&report, OutputFormat::Text),
+            "tokenizer gpt2\nruns 2\nfiles 10\nbytes 4000\ntokens 1000\n\
+             time 2.000s total, 0.500s min, 1.000s mean\ntokens/s 1000\nfiles/s 10\n\
+             bytes/s 4000\n"
+        );
+        let json: Value = serde_json::from_str(&benchmark(&report, OutputFormat::Json)).unwrap();
+        assert_eq!(json["tokens_per_second"], 1000.0);
+        assert_eq!(json["runs"], 2);
+    }
+
+    #[test]
+    fn test_tokenizer_info() {
+        let infos = [
+            TokenizerInfo {
+                name: "gpt2".to_string(),
+                source: "--identifier".to_string(),
+                vocab_size: Some(50257),
+                special_tokens: vec!["<|endoftext|>".to_string()],
+                fingerprint: Some("abc".to_string()),
+            },
+            TokenizerInfo {
+                name: "words".to_string(),
+                source: "--count-mode".to_string(),
+                ..TokenizerInfo::default()
+            },
+        ];
+        assert_eq!(
+            tokenizer_info(&infos, OutputFormat::Text),
+            "name: gpt2\nsource: --identifier\nvocab size: 50257\nspecial tokens: <|endoftext|>\n\
+             fingerprint: abc\n\nname: words\nsource: --count-mode\nvocab size: -\n\
+             special tokens: -\nfingerprint: -\n"
+        );
+        assert_eq!(
+            tokenizer_info(&infos[1..], OutputFormat::Csv),
+            "name,source,vocab_size,special_tokens,fingerprint\nwords,--count-mode,,,\n"
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        let before = vec![
+            file_count("a.txt", 5),
+            file_count("b.txt", 3),
+            file_count("c.txt", 7),
+        ];
+        let after = vec![
+            file_count("a.txt", 8),
+            file_count("c.txt", 7),
+            file_count("d.txt", 4),
+        ];
+        let deltas = compare_counts(before, after);
+        assert_eq!(deltas.len(), 3);
+        let expected = [
+            "~ a.txt 5 -> 8 (+3)",
+            "- b.txt 3",
+            "+ d.txt 4",
+            "added 1 files +4 tokens",
+            "removed 1 files -3 tokens",
+            "changed 1 files +3 tokens",
+            "total 15 -> 19 (+4)",
+        ];
+        assert_eq!(
+            comparison(&deltas, 15, 19, true, OutputFormat::Text),
+            expected.join("\n") + "\n"
+        );
+        assert_eq!(
+            comparison(&deltas[1..2], 3, 0, true, OutputFormat::Csv),
+            "path,before,after,delta\nb.txt,3,,-3\ntotal,3,0,-3\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let counts: Vec<FileCount> = [3, 2, 100, 120, 90, 127]
+            .iter()
+            .map(|tokens| file_count("a.txt", *tokens))
+            .collect();
+        let expected = [
+            "   2-3 | ######################### 2",
+            "   4-7 |  0",
+            "  8-15 |  0",
+            " 16-31 |  0",
+            " 32-63 |  0",
+            "64-127 | ################################################## 4",
+        ];
+        assert_eq!(histogram(&counts), expected.join("\n") + "\n");
+        assert_eq!(
+            histogram(&[file_count("a.txt", 0)]),
+            "0 | ".to_string() + &"#".repeat(50) + " 1\n"
+        );
+        assert_eq!(histogram(&[]), "");
+    }
+
+    #[test]
+    fn test_min_tokens() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, true).with_min_tokens(Some(3));
+        writer.add(file_count("a.txt", 1));
+        writer.add(file_count("b.txt", 5));
+        writer.add(file_count("c.txt", 3));
+        assert_eq!(writer.summary(), "files=3 tokens=9 avg=3.0");
+        assert_eq!(render(writer), "b.txt 5\nc.txt 3\ntotal 9\n");
+    }
+
+    #[test]
+    fn test_context_window_overflow() {
+        let mut writer = OutputWriter::new(OutputFormat::Json, false).with_context_window(Some(4));
+        writer.add(file_count("a.txt", 2));
+        writer.add(file_count("b.txt", 7));
+        writer.add(file_count("c.txt", 5));
+        assert_eq!(render(writer), "{\"overflow\":4,\"total\":14}\n");
+    }
+
+    #[test]
+    fn test_chunking() {
+        assert_eq!(chunk_count(0, 512, 64), 0);
+        assert_eq!(chunk_count(512, 512, 64), 1);
+        assert_eq!(chunk_count(513, 512, 64), 2);
+        assert_eq!(chunk_count(960, 512, 64), 2);
+        assert_eq!(chunk_count(961, 512, 64), 3);
+        assert_eq!(chunk_count(10, 4, 0), 3);
+        let mut writer = OutputWriter::new(OutputFormat::Text, true).with_chunking(Some(4), 1);
+        writer.add(file_count("a.txt", 4));
+        writer.add(file_count("b.txt", 8));
+        assert_eq!(render(writer), "a.txt 4 1\nb.txt 8 3\ntotal 12 4\n");
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, false).with_budget(Some(8000));
+        writer.add(file_count("a.txt", 1234));
+        assert_eq!(render(writer), "1234/8000 (15.4%)\n");
+
+        let mut writer = OutputWriter::new(OutputFormat::Text, true).with_budget(Some(4));
+        writer.add(file_count("a.txt", 1));
+        writer.add(file_count("b.txt", 5));
+        assert_eq!(
+            render(writer),
+            "a.txt 1/4 (25.0%)\nb.txt 5/4 (125.0%)\ntotal 6/4 (150.0%)\n"
+        );
+
+        let mut writer = OutputWriter::new(OutputFormat::Json, false).with_budget(Some(3));
+        writer.add(file_count("a.txt", 2));
+        assert_eq!(render(writer), "{\"budget_percent\":66.7,\"total\":2}\n");
+    }
+
+    #[test]
+    fn test_template() {
+        let template = Template::parse("{tokens}\\t{path} {{x}}").unwrap();
+        let mut writer = OutputWriter::new(OutputFormat::Text, true)
+            .with_stats(true)
+            .with_template(Some(template));
+        writer.add(file_count("a.txt", 2));
+        assert_eq!(render(writer), "2\ta.txt {x}\n2\ttotal {x}\n");
+        assert_eq!(
+            Template::parse("{size}"),
+            Err(TemplateError::UnknownPlaceholder("size".to_string()))
+        );
+        assert_eq!(
+            Template::parse("{path"),
+            Err(TemplateError::UnclosedPlaceholder)
+        );
+    }
+
+    #[test]
+    fn test_json_unit() {
+        let mut writer =
+            OutputWriter::new(OutputFormat::Json, false).with_unit(Some("lines".to_string()));
+        writer.add(file_count("a.txt", 2));
+        assert_eq!(render(writer), "{\"lines\":2}\n");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut writer = OutputWriter::new(OutputFormat::Text, false);
+        assert_eq!(writer.summary(), "files=0 tokens=0 avg=0.0");
+        writer.add(file_count("a.txt", 2));
+        writer.add(file_count("b.txt", 3));
+        assert_eq!(writer.summary(), "files=2 tokens=5 avg=2.5");
+        let mut writer = OutputWriter::new(OutputFormat::Text, true).with_per_line(true);
+        writer.add(file_count("a.jsonl:1", 2));
+        writer.add(file_count("a.jsonl:2", 7));
+        assert_eq!(writer.summary(), "lines=2 tokens=9 min=2 max=7 avg=4.5");
+    }
+
+    #[test]
+    fn test_text_sort_tokens() {
+        let mut writer =
+            OutputWriter::new(OutputFormat::Text, true).with_sort(SortOrder::TokensAsc);
+        writer.add(file_count("a.txt", 5));
+        writer.add(file_count("b.txt", 1));
+        writer.add(file_count("c.txt", 3));
+        assert_eq!(render(writer), "b.txt 1\nc.txt 3\na.txt 5\n");
+    }
+
+    #[test]
+    fn test_group_by_directory() {
+        let mut writer =
+            OutputWriter::new(OutputFormat::Text, false).with_group_by(Some(GroupBy::Directory));
+        writer.add(file_count("b/c.txt", 1));
+        writer.add(file_count("a.txt", 2));
+        writer.add(file_count("b/d.txt", 3));
+        writer.add(file_count("b/e/f.txt", 4));
+        assert_eq!(render(writer), ". 2\nb 4\nb/e 4\ntotal 10\n");
+    }
+
+    #[test]
+    fn test_group_by_extension() {
+        let mut writer =
+            OutputWriter::new(OutputFormat::Text, false).with_group_by(Some(GroupBy::Extension));
+        writer.add(file_count("a.md", 1));
+        writer.add(file_count("src/b.rs", 3));
+        writer.add(file_count("Makefile", 2));
+        writer.add(file_count("c.md", 4));
+        assert_eq!(render(writer), ".md 5\n.rs 3\n(none) 2\ntotal 10\n");
+    }
+
+    #[test]
+    fn test_group_by_regex() {
+        let regex = Regex::new(r"_(en|de)\.").unwrap();
+        let mut writer =
+            OutputWriter::new(OutputFormat::Text, false).with_group_by(Some(GroupBy::Regex(regex)));
+        writer.add(file_count("docs/intro_en.md", 1));
+        writer.add(file_count("docs/intro_de.md", 3));
+        writer.add(file_count("guide_en.txt", 4));
+        writer.add(file_count("README.md", 2));
+        assert_eq!(render(writer), "en 5\nde 3\n(unmatched) 2\ntotal 10\n");
+        let whole_match = GroupBy::Regex(Regex::new(r"^[a-z]+").unwrap());
+        assert_eq!(whole_match.key(Path::new("docs/a.md")), "docs");
+    }
+
+    #[test]
+    fn test_csv_tokenizer_columns() {
+        let mut writer = OutputWriter::new(OutputFormat::Csv, true)
+            .with_tokenizer_names(vec!["bert".to_string(), "gpt2".to_string()]);
+        let mut count = file_count("a.txt", 2);
+        count.other_tokens = vec![3];
+        writer.add(count);
+        assert_eq!(render(writer), "path,bert,gpt2\na.txt,2,3\n");
+    }
+
+    #[test]
+    fn test_offsets_formats() {
+        let tokens = vec![
+            TokenOffset {
+                id: 7,
+                token: "a\tb".to_string(),
+                start: 0,
+                end: 3,
+            },
+            TokenOffset {
+                id: 2,
+                token: "[SEP]".to_string(),
+                start: 0,
+                end: 0,
+            },
+        ];
+        assert_eq!(
+            offsets(&tokens, OutputFormat::Text),
+            "id\ttoken\tstart\tend\n7\ta\\tb\t0\t3\n2\t[SEP]\t0\t0\n"
+        );
+        assert_eq!(
+            offsets(&tokens[1..], OutputFormat::Json),
+            "[{\"end\":0,\"id\":2,\"start\":0,\"token\":\"[SEP]\"}]\n"
+        );
+    }
+}
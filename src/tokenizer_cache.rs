@@ -0,0 +1,203 @@
+use crate::Result;
+use log::debug;
+use std::env;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+/// Environment variable to override cache directory
+pub const TOKEN_COUNT_CACHE_VAR: &str = "TOKEN_COUNT_CACHE";
+const HF_HOME_VAR: &str = "HF_HOME";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const LOCK_FILE: &str = "tokenizer.json.lock";
+
+/// Local storage of downloaded tokenizers keyed by identifier and revision
+pub struct TokenizerCache {
+    dir: PathBuf,
+}
+
+impl TokenizerCache {
+    pub fn new(dir: PathBuf) -> Self {
+        TokenizerCache { dir }
+    }
+
+    /// Cache directory is taken from `TOKEN_COUNT_CACHE`, then `$HF_HOME/tokencount`,
+    /// then `~/.cache/huggingface/tokencount`
+    pub fn from_env() -> Option<Self> {
+        if let Ok(dir) = env::var(TOKEN_COUNT_CACHE_VAR) {
+            return Some(TokenizerCache::new(PathBuf::from(dir)));
+        }
+        let hf_home = env::var(HF_HOME_VAR)
+            .map(PathBuf::from)
+            .ok()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".cache").join("huggingface")))?;
+        Some(TokenizerCache::new(hf_home.join("tokencount")))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of cached tokenizer file for identifier and revision
+    pub fn path(&self, identifier: &str, revision: &str) -> PathBuf {
+        self.dir
+            .join(sanitize(identifier))
+            .join(sanitize(revision))
+            .join(TOKENIZER_FILE)
+    }
+
+    pub fn load(&self, identifier: &str, revision: &str) -> Option<Tokenizer> {
+        let path = self.path(identifier, revision);
+        if !path.is_file() {
+            return None;
+        }
+        Tokenizer::from_file(path).ok()
+    }
+
+    /// Locks cache entry of identifier and revision, so parallel processes do not download
+    /// the same tokenizer. Waits while another process holds the lock, which is released
+    /// when returned guard is dropped.
+    pub fn lock(&self, identifier: &str, revision: &str) -> Result<CacheLock> {
+        let path = self.path(identifier, revision);
+        let folder = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(folder)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(folder.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                debug!(
+                    "Waiting for another process to download {identifier} (revision {revision})"
+                );
+                file.lock()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        Ok(CacheLock { _file: file })
+    }
+
+    /// Saves tokenizer to cache. File is written to temporary location first and then
+    /// renamed, so readers never see partially written file.
+    pub fn store(&self, identifier: &str, revision: &str, tokenizer: &Tokenizer) -> Result<()> {
+        let path = self.path(identifier, revision);
+        let folder = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(folder)?;
+        let tmp_path = folder.join(format!("{TOKENIZER_FILE}.{}.tmp", std::process::id()));
+        tokenizer.save(&tmp_path, false)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Exclusive lock of cache entry, released on drop
+pub struct CacheLock {
+    _file: File,
+}
+
+/// Pairs models with revisions: one revision applies to every model, one model is loaded at
+/// every revision, otherwise models and revisions are paired in order
+pub fn model_revisions(models: &[String], revisions: &[String]) -> Result<Vec<(String, String)>> {
+    let pairs = match (models, revisions) {
+        (_, [revision]) => models
+            .iter()
+            .map(|model| (model.clone(), revision.clone()))
+            .collect(),
+        ([model], _) => revisions
+            .iter()
+            .map(|revision| (model.clone(), revision.clone()))
+            .collect(),
+        _ if models.len() == revisions.len() => models
+            .iter()
+            .cloned()
+            .zip(revisions.iter().cloned())
+            .collect(),
+        _ => {
+            return Err(format!(
+                "{} revisions cannot be paired with {} identifiers, pass one revision or one \
+                 revision per identifier",
+                revisions.len(),
+                models.len()
+            )
+            .into());
+        }
+    };
+    Ok(pairs)
+}
+
+fn sanitize(key: &str) -> String {
+    key.replace(['/', '\\'], "--")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = env::temp_dir().join(format!("tokencount-cache-test-{}", std::process::id()));
+        let cache = TokenizerCache::new(dir.clone());
+        assert!(cache.load("org/model", "main").is_none());
+        let tokenizer = Tokenizer::from_file("resources/tokenizer/tokenizer.json").unwrap();
+        cache.store("org/model", "main", &tokenizer).unwrap();
+        assert_eq!(
+            cache.path("org/model", "main"),
+            dir.join("org--model").join("main").join("tokenizer.json")
+        );
+        assert!(cache.load("org/model", "main").is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_model_revisions() {
+        let strings = |values: &[&str]| -> Vec<String> {
+            values.iter().map(|value| value.to_string()).collect()
+        };
+        let pairs = |values: &[(&str, &str)]| -> Vec<(String, String)> {
+            values
+                .iter()
+                .map(|(model, revision)| (model.to_string(), revision.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            model_revisions(&strings(&["a", "b"]), &strings(&["main"])).unwrap(),
+            pairs(&[("a", "main"), ("b", "main")])
+        );
+        assert_eq!(
+            model_revisions(&strings(&["a"]), &strings(&["v1", "v2"])).unwrap(),
+            pairs(&[("a", "v1"), ("a", "v2")])
+        );
+        assert_eq!(
+            model_revisions(&strings(&["a", "b"]), &strings(&["v1", "v2"])).unwrap(),
+            pairs(&[("a", "v1"), ("b", "v2")])
+        );
+        let error = model_revisions(&strings(&["a", "b", "c"]), &strings(&["v1", "v2"]));
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "2 revisions cannot be paired with 3 identifiers, pass one revision or one revision \
+             per identifier"
+        );
+        assert!(model_revisions(&strings(&["a", "b"]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = env::temp_dir().join(format!("tokencount-lock-test-{}", std::process::id()));
+        let cache = TokenizerCache::new(dir.clone());
+        let lock = cache.lock("org/model", "main").unwrap();
+        let locked = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _lock = cache.lock("org/model", "main").unwrap();
+                locked.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!locked.load(std::sync::atomic::Ordering::SeqCst));
+            drop(lock);
+        });
+        assert!(locked.load(std::sync::atomic::Ordering::SeqCst));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Longest unterminated placeholder carried over to the following text, longer `{{` are
+/// treated as plain text
+const MAX_PLACEHOLDER_LENGTH: usize = 256;
+
+/// Values of `{{name}}` placeholders of prompt templates, substituted before counting.
+/// Whitespace around the name is ignored, e.g. `{{ name }}`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Variables {
+    values: HashMap<String, String>,
+}
+
+impl Variables {
+    pub fn new(values: HashMap<String, String>) -> Self {
+        Variables { values }
+    }
+
+    /// Parses `key=value` assignment, value may contain `=`
+    pub fn parse_assignment(assignment: &str) -> Result<(String, String), String> {
+        match assignment.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.to_string()))
+            }
+            _ => Err(format!("Invalid variable {assignment}, expected key=value")),
+        }
+    }
+
+    /// Replaces placeholders with values, placeholders without value are kept and their
+    /// names returned
+    pub fn substitute<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<&'a str>) {
+        let mut missing = Vec::new();
+        if !text.contains("{{") {
+            return (Cow::Borrowed(text), missing);
+        }
+        let mut substituted = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(length) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let end = start + 2 + length + 2;
+            let name = rest[start + 2..end - 2].trim();
+            substituted.push_str(&rest[..start]);
+            match self.values.get(name) {
+                Some(value) => substituted.push_str(value),
+                None => {
+                    missing.push(name);
+                    substituted.push_str(&rest[start..end]);
+                }
+            }
+            rest = &rest[end..];
+        }
+        substituted.push_str(rest);
+        (Cow::Owned(substituted), missing)
+    }
+
+    /// Start of placeholder which is not terminated at the end of `text` and may continue in
+    /// text that follows it, e.g. in the next window of a file. `text.len()` if there is none.
+    pub fn unterminated_start(text: &str) -> usize {
+        if let Some(start) = text.rfind("{{")
+            && !text[start + 2..].contains("}}")
+            && text.len() - start <= MAX_PLACEHOLDER_LENGTH
+        {
+            return start;
+        }
+        if text.ends_with('{') {
+            return text.len() - 1;
+        }
+        text.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        let variables = Variables::new(HashMap::from([
+            ("name".to_string(), "Alice".to_string()),
+            ("task".to_string(), "a=b".to_string()),
+        ]));
+        let (text, missing) = variables.substitute("Hi {{name}}, do {{ task }} in {{lang}} {{x");
+        assert_eq!(text, "Hi Alice, do a=b in {{lang}} {{x");
+        assert_eq!(missing, vec!["lang"]);
+        let (text, missing) = variables.substitute("no placeholders");
+        assert!(matches!(text, Cow::Borrowed(_)));
+        assert!(missing.is_empty());
+        assert_eq!(
+            Variables::parse_assignment("task=a=b"),
+            Ok(("task".to_string(), "a=b".to_string()))
+        );
+        assert!(Variables::parse_assignment("=value").is_err());
+        assert!(Variables::parse_assignment("value").is_err());
+    }
+
+    #[test]
+    fn test_unterminated_start() {
+        assert_eq!(Variables::unterminated_start("Hi {{name}}"), 11);
+        assert_eq!(Variables::unterminated_start("Hi {{na"), 3);
+        assert_eq!(Variables::unterminated_start("Hi {{name}"), 3);
+        assert_eq!(Variables::unterminated_start("Hi {"), 3);
+        let long = format!("{{{{{}", "x".repeat(MAX_PLACEHOLDER_LENGTH));
+        assert_eq!(Variables::unterminated_start(&long), long.len());
+    }
+}